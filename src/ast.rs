@@ -0,0 +1,141 @@
+use serde_json::Value;
+use std::fmt;
+use std::rc::Rc;
+
+/// A single navigation step inside a path expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    /// `*`: every array element or object value, in key order
+    Any,
+    /// `[]`: like `*`, but outputs the results one by one instead of as an array
+    Iterate,
+    /// `*~`: like `*`, but yields `{"key": ..., "value": ...}` entries
+    Entries,
+    /// An object key; on arrays a numeric key such as `people.0` also indexes
+    Key(String),
+    /// An index written in brackets, e.g. `people[0]`; negative counts from the end
+    Index(i64),
+    /// `..key`, `..*`: the token applied at every depth below the current value
+    Recurse(Box<Token>),
+    /// `[?(condition)]`: the elements or values for which the condition is
+    /// truthy, so `[?(@.key)]` drops those where `key` is `false` or `null`
+    /// as well as those without it; `[?(has("key"))]` tests presence
+    Filter(Box<Expr>),
+    /// `token?`: the token yields nothing instead of failing
+    Optional(Box<Token>),
+    /// A Python-style `[start:stop:step]` slice
+    Slice(Option<i64>, Option<i64>, Option<i64>),
+    /// `[expr]`: the key or index `expr` evaluates to, against the input of
+    /// the whole path
+    Dynamic(Box<Expr>),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Any => write!(f, ".*"),
+            Token::Iterate => write!(f, "[]"),
+            Token::Entries => write!(f, ".*~"),
+            Token::Recurse(token) => write!(f, ".{}", token),
+            Token::Filter(_) => write!(f, "[?(...)]"),
+            Token::Dynamic(_) => write!(f, "[...]"),
+            Token::Optional(token) => write!(f, "{}?", token),
+            Token::Key(key)
+                if !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+            {
+                write!(f, ".{}", key)
+            }
+            Token::Key(key) => write!(f, "[{}]", Value::from(key.as_str())),
+            Token::Index(index) => write!(f, "[{}]", index),
+            Token::Slice(start, stop, step) => {
+                let bound = |bound: &Option<i64>| bound.map_or(String::new(), |b| b.to_string());
+                write!(f, "[{}:{}", bound(start), bound(stop))?;
+                if step.is_some() {
+                    write!(f, ":{}", bound(step))?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    StartsWith,
+    EndsWith,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    /// `left // right`: the truthy outputs of `left`, or those of `right` if
+    /// there are none
+    Alternative,
+    And,
+    Or,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// The current input, written `.` (or `@` inside predicates)
+    Identity,
+    Literal(Value),
+    /// `$name`
+    Variable(String),
+    /// `source as $name | body`: `body` evaluated with `$name` bound to each
+    /// output of `source` in turn
+    Bind(Box<Expr>, String, Box<Expr>),
+    /// A base expression followed by path tokens, e.g. `people.0.email`
+    Path(Box<Expr>, Vec<Token>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Call(String, Vec<Expr>),
+    /// `left | right`: `right` evaluated against every output of `left`
+    Pipe(Box<Expr>, Box<Expr>),
+    /// `left, right`: the outputs of `left` followed by those of `right`
+    Comma(Box<Expr>, Box<Expr>),
+    /// `[expr]`: an array of every output of `expr`
+    Array(Box<Expr>),
+    /// `{key: value, ...}`: an object for every combination of the outputs of
+    /// its keys and values
+    Object(Vec<(Expr, Expr)>),
+    /// `if condition then a else b end`; `elif` nests another `If` as the
+    /// else branch
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `reduce source as $name (init; update)`: `update` applied to the
+    /// accumulator for each output of `source`, outputting the final value
+    Reduce(Box<Expr>, String, Box<Expr>, Box<Expr>),
+    /// `foreach source as $name (init; update; extract)`: like `reduce`, but
+    /// outputs `extract` (or the accumulator itself) after every update
+    Foreach(Box<Expr>, String, Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+    /// `"text \(expr)"`: a string for every combination of the outputs of the
+    /// parts, with strings joined as they are and other values as JSON
+    Template(Vec<Expr>),
+    /// `def ...; rest`: `rest` evaluated with the function defined
+    Def(Rc<Definition>, Box<Expr>),
+    /// `import "path" as alias; rest`: `rest` evaluated with the functions
+    /// defined by the module, a chain of `Def`s around `.`, callable as
+    /// `alias::name`
+    Import(Rc<Expr>, String, Box<Expr>),
+}
+
+/// `def name(params): body;`. Parameters are filters evaluated where they are
+/// used; a `$param` is parsed as a filter parameter bound to `$param` in `body`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+}
@@ -0,0 +1,1270 @@
+use crate::ast::BinaryOp;
+use crate::ast::Expr;
+use crate::filter::{eval, holds, truthy, Options};
+use crate::patterns;
+use crate::scope::Scope;
+use crate::value::{arithmetic, compare, compare_numbers, describe, sum, type_name};
+use crate::{Result, YajqError};
+use serde_json::{json, Map, Number, Value};
+use std::cmp::Ordering;
+use std::env;
+
+/// A function that can be called from an expression, e.g. `length` or
+/// `has("name")`.
+#[derive(Copy, Clone)]
+pub enum Builtin {
+    /// Computed from the input and the values of the arguments; called once
+    /// for every combination of the arguments' outputs
+    Native(fn(&Value, &[Value]) -> Result<Value>),
+    /// Given the argument expressions themselves, so it can decide what to
+    /// evaluate them against and how many values to output
+    Generator(Generator),
+}
+
+type Generator =
+    fn(&Value, &[Expr], &Options, &Scope, &mut dyn FnMut(Value) -> Result<()>) -> Result<()>;
+
+/// Finds the built-in called `name` taking `arity` arguments.
+pub fn lookup(name: &str, arity: usize) -> Option<Builtin> {
+    Some(match (name, arity) {
+        ("length", 0) => Builtin::Native(length),
+        ("keys", 0) => Builtin::Native(keys),
+        ("values", 0) => Builtin::Native(values),
+        ("type", 0) => Builtin::Native(|input, _| Ok(Value::from(type_name(input)))),
+        ("has", 1) => Builtin::Native(has),
+        ("env", 1) => {
+            Builtin::Native(|_, arguments| match &arguments[0] {
+                Value::String(name) => Ok(env::var_os(name)
+                    .map_or(Value::Null, |value| Value::from(value.to_string_lossy()))),
+                name => Err(YajqError::Type(format!(
+                    "Environment variable names must be strings, not {}",
+                    describe(name)
+                ))),
+            })
+        }
+        ("not", 0) => Builtin::Native(|input, _| Ok(Value::Bool(!truthy(input)))),
+        ("empty", 0) => Builtin::Generator(|_, _, _, _, _| Ok(())),
+        ("error", 0) => Builtin::Native(|input, _| Err(YajqError::Raised(input.to_owned()))),
+        ("error", 1) => {
+            Builtin::Native(|_, arguments| Err(YajqError::Raised(arguments[0].to_owned())))
+        }
+        ("map", 1) => Builtin::Generator(map),
+        ("flat_map", 1) => Builtin::Generator(flat_map),
+        ("select", 1) => Builtin::Generator(select),
+        ("any", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let mut found = false;
+            for element in elements(input, "any")? {
+                if holds(&arguments[0], element, options, scope)? {
+                    found = true;
+                    break;
+                }
+            }
+            out(Value::Bool(found))
+        }),
+        ("all", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let mut found = true;
+            for element in elements(input, "all")? {
+                if !holds(&arguments[0], element, options, scope)? {
+                    found = false;
+                    break;
+                }
+            }
+            out(Value::Bool(found))
+        }),
+        ("sort", 0) => Builtin::Native(|input, _| {
+            let mut sorted = array(input, "sort")?.to_owned();
+            sorted.sort_by(compare);
+            Ok(Value::Array(sorted))
+        }),
+        ("sort_by", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let sorted = sorted_by(input, &arguments[0], options, scope, "sort_by")?;
+            out(sorted
+                .into_iter()
+                .map(|(_, element)| element.to_owned())
+                .collect())
+        }),
+        ("group_by", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let sorted = sorted_by(input, &arguments[0], options, scope, "group_by")?;
+            out(groups(sorted)
+                .map(|group| {
+                    group
+                        .iter()
+                        .map(|(_, element)| (*element).to_owned())
+                        .collect::<Value>()
+                })
+                .collect())
+        }),
+        ("unique", 0) => Builtin::Native(|input, _| {
+            let mut unique = array(input, "unique")?.to_owned();
+            unique.sort_by(compare);
+            unique.dedup_by(|left, right| compare(left, right) == Ordering::Equal);
+            Ok(Value::Array(unique))
+        }),
+        ("unique_by", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let sorted = sorted_by(input, &arguments[0], options, scope, "unique_by")?;
+            out(groups(sorted).map(|group| group[0].1.to_owned()).collect())
+        }),
+        ("min_by", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let keyed = keyed(input, &arguments[0], options, scope, "min_by")?;
+            out(keyed
+                .into_iter()
+                .min_by(|(left, _), (right, _)| compare(left, right))
+                .map_or(Value::Null, |(_, element)| element.to_owned()))
+        }),
+        ("max_by", 1) => Builtin::Generator(|input, arguments, options, scope, out| {
+            let keyed = keyed(input, &arguments[0], options, scope, "max_by")?;
+            out(keyed
+                .into_iter()
+                .max_by(|(left, _), (right, _)| compare(left, right))
+                .map_or(Value::Null, |(_, element)| element.to_owned()))
+        }),
+        ("reverse", 0) => Builtin::Native(reverse),
+        ("add", 0) => Builtin::Native(|input, _| {
+            elements(input, "add")?
+                .into_iter()
+                .try_fold(Value::Null, |total, element| {
+                    arithmetic(BinaryOp::Add, &total, element)
+                })
+        }),
+        ("sum", 0) => Builtin::Native(|input, _| sum(&numbers(input, "sum")?)),
+        ("avg", 0) => Builtin::Native(|input, _| {
+            let numbers = numbers(input, "avg")?;
+            if numbers.is_empty() {
+                return Ok(Value::Null);
+            }
+            arithmetic(BinaryOp::Div, &sum(&numbers)?, &Value::from(numbers.len()))
+        }),
+        ("min", 0) => Builtin::Native(|input, _| {
+            Ok(array(input, "min")?
+                .iter()
+                .min_by(|left, right| compare(left, right))
+                .map_or(Value::Null, Value::to_owned))
+        }),
+        ("max", 0) => Builtin::Native(|input, _| {
+            Ok(array(input, "max")?
+                .iter()
+                .max_by(|left, right| compare(left, right))
+                .map_or(Value::Null, Value::to_owned))
+        }),
+        ("count", 0) => {
+            Builtin::Native(|input, _| Ok(Value::from(elements(input, "count")?.len())))
+        }
+        ("median", 0) => Builtin::Native(median),
+        ("percentile", 1) => Builtin::Native(percentile),
+        ("stddev", 0) => Builtin::Native(stddev),
+        ("histogram", 1) => Builtin::Native(histogram),
+        ("split", 1) => Builtin::Native(split),
+        ("join", 1) => Builtin::Native(join),
+        ("ascii_downcase", 0) => Builtin::Native(|input, _| {
+            Ok(Value::from(
+                text(input, "ascii_downcase")?.to_ascii_lowercase(),
+            ))
+        }),
+        ("ascii_upcase", 0) => Builtin::Native(|input, _| {
+            Ok(Value::from(
+                text(input, "ascii_upcase")?.to_ascii_uppercase(),
+            ))
+        }),
+        ("trim", 0) => Builtin::Native(|input, _| Ok(Value::from(text(input, "trim")?.trim()))),
+        ("ltrim", 0) => {
+            Builtin::Native(|input, _| Ok(Value::from(text(input, "ltrim")?.trim_start())))
+        }
+        ("rtrim", 0) => {
+            Builtin::Native(|input, _| Ok(Value::from(text(input, "rtrim")?.trim_end())))
+        }
+        ("startswith", 1) => Builtin::Native(|input, arguments| {
+            let prefix = text(&arguments[0], "startswith")?;
+            Ok(Value::Bool(text(input, "startswith")?.starts_with(prefix)))
+        }),
+        ("endswith", 1) => Builtin::Native(|input, arguments| {
+            let suffix = text(&arguments[0], "endswith")?;
+            Ok(Value::Bool(text(input, "endswith")?.ends_with(suffix)))
+        }),
+        ("contains", 1) => {
+            Builtin::Native(|input, arguments| contains(input, &arguments[0]).map(Value::Bool))
+        }
+        ("ltrimstr", 1) => Builtin::Native(|input, arguments| {
+            Ok(match (input, &arguments[0]) {
+                (Value::String(string), Value::String(prefix)) => {
+                    Value::from(string.strip_prefix(prefix.as_str()).unwrap_or(string))
+                }
+                _ => input.to_owned(),
+            })
+        }),
+        ("rtrimstr", 1) => Builtin::Native(|input, arguments| {
+            Ok(match (input, &arguments[0]) {
+                (Value::String(string), Value::String(suffix)) => {
+                    Value::from(string.strip_suffix(suffix.as_str()).unwrap_or(string))
+                }
+                _ => input.to_owned(),
+            })
+        }),
+        ("replace", 2) => Builtin::Native(|input, arguments| {
+            let from = text(&arguments[0], "replace")?;
+            let to = text(&arguments[1], "replace")?;
+            Ok(Value::from(text(input, "replace")?.replace(from, to)))
+        }),
+        ("pad", 1) => Builtin::Native(pad),
+        ("pad", 2) => Builtin::Native(pad),
+        ("substr", 1) => Builtin::Native(substr),
+        ("substr", 2) => Builtin::Native(substr),
+        ("test", 1) | ("test", 2) => Builtin::Native(patterns::test),
+        ("match", 1) | ("match", 2) => Builtin::Generator(patterns::matches),
+        ("capture", 1) | ("capture", 2) => Builtin::Generator(patterns::capture),
+        ("scan", 1) | ("scan", 2) => Builtin::Generator(patterns::scan),
+        ("sub", 2) | ("sub", 3) => Builtin::Generator(patterns::sub),
+        ("gsub", 2) | ("gsub", 3) => Builtin::Generator(patterns::gsub),
+        _ => return None,
+    })
+}
+
+/// The elements of an array, which ordering functions such as `sort` need.
+fn array<'a>(input: &'a Value, name: &str) -> Result<&'a Vec<Value>> {
+    match input {
+        Value::Array(array) => Ok(array),
+        _ => Err(YajqError::Type(format!(
+            "{} needs an array, not {}",
+            name,
+            describe(input)
+        ))),
+    }
+}
+
+/// The numbers in an array, which statistics such as `median` need.
+fn numbers<'a>(input: &'a Value, name: &str) -> Result<Vec<&'a Number>> {
+    array(input, name)?
+        .iter()
+        .map(|element| match element {
+            Value::Number(number) => Ok(number),
+            _ => Err(YajqError::Type(format!(
+                "{} needs numbers, not {}",
+                name,
+                describe(element)
+            ))),
+        })
+        .collect()
+}
+
+/// The numbers in an array as sorted floats.
+fn sorted_floats(input: &Value, name: &str) -> Result<Vec<f64>> {
+    let mut floats: Vec<f64> = numbers(input, name)?
+        .iter()
+        .map(|number| number.as_f64().unwrap())
+        .collect();
+    floats.sort_by(|left, right| left.partial_cmp(right).unwrap());
+    Ok(floats)
+}
+
+/// A computed statistic as a number, an integer if it is a whole one.
+fn statistic(value: f64) -> Result<Value> {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        return Ok(Value::from(value as i64));
+    }
+    Number::from_f64(value)
+        .map(Value::Number)
+        .ok_or_else(|| YajqError::Arithmetic(format!("{} is not a finite number", value)))
+}
+
+/// Each element of an array with its key: an array of every output of `f`
+/// for it.
+fn keyed<'a>(
+    input: &'a Value,
+    f: &Expr,
+    options: &Options,
+    scope: &Scope,
+    name: &str,
+) -> Result<Vec<(Value, &'a Value)>> {
+    let mut keyed = Vec::new();
+    for element in array(input, name)? {
+        let mut key = Vec::new();
+        eval(f, element, options, scope, &mut |value| {
+            key.push(value);
+            Ok(())
+        })?;
+        keyed.push((Value::Array(key), element));
+    }
+    Ok(keyed)
+}
+
+/// The elements of an array stably sorted by their keys.
+fn sorted_by<'a>(
+    input: &'a Value,
+    f: &Expr,
+    options: &Options,
+    scope: &Scope,
+    name: &str,
+) -> Result<Vec<(Value, &'a Value)>> {
+    let mut sorted = keyed(input, f, options, scope, name)?;
+    sorted.sort_by(|(left, _), (right, _)| compare(left, right));
+    Ok(sorted)
+}
+
+/// The runs of sorted elements with equal keys.
+fn groups(sorted: Vec<(Value, &Value)>) -> impl Iterator<Item = Vec<(Value, &Value)>> {
+    let mut sorted = sorted.into_iter().peekable();
+    std::iter::from_fn(move || {
+        let first = sorted.next()?;
+        let mut group = vec![first];
+        while let Some(next) =
+            sorted.next_if(|(key, _)| compare(key, &group[0].0) == Ordering::Equal)
+        {
+            group.push(next);
+        }
+        Some(group)
+    })
+}
+
+/// The elements of an array or the values of an object, which collection
+/// functions such as `map` work through.
+fn elements<'a>(input: &'a Value, name: &str) -> Result<Vec<&'a Value>> {
+    match input {
+        Value::Array(array) => Ok(array.iter().collect()),
+        Value::Object(object) => Ok(object.values().collect()),
+        _ => Err(YajqError::Type(format!(
+            "{} needs an array or object, not {}",
+            name,
+            describe(input)
+        ))),
+    }
+}
+
+/// `map(f)`: an array of every output of `f` for every element.
+fn map(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let mut mapped = Vec::new();
+    for element in elements(input, "map")? {
+        eval(&arguments[0], element, options, scope, &mut |value| {
+            mapped.push(value);
+            Ok(())
+        })?;
+    }
+    out(Value::Array(mapped))
+}
+
+/// `flat_map(f)`: like `map(f)`, but the elements of array outputs are
+/// added instead of the arrays themselves.
+fn flat_map(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let mut mapped = Vec::new();
+    for element in elements(input, "flat_map")? {
+        eval(&arguments[0], element, options, scope, &mut |value| {
+            match value {
+                Value::Array(values) => mapped.extend(values),
+                value => mapped.push(value),
+            }
+            Ok(())
+        })?;
+    }
+    out(Value::Array(mapped))
+}
+
+/// `select(cond)`: the input if the condition holds for it, and nothing
+/// otherwise. On an array, the elements for which it holds, like `[?(cond)]`,
+/// so `people | select(cond) | map(f)` works; an array is kept or dropped
+/// whole with `if cond then . else empty end`.
+fn select(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let condition = &arguments[0];
+    match input {
+        Value::Array(array) => {
+            let mut selected = Vec::new();
+            for element in array {
+                if holds(condition, element, options, scope)? {
+                    selected.push(element.to_owned());
+                }
+            }
+            out(Value::Array(selected))
+        }
+        input if holds(condition, input, options, scope)? => out(input.to_owned()),
+        _ => Ok(()),
+    }
+}
+
+/// The process environment as an object, the value of `$ENV`.
+pub fn environment() -> Value {
+    env::vars_os()
+        .map(|(name, value)| {
+            (
+                name.to_string_lossy().into_owned(),
+                Value::from(value.to_string_lossy()),
+            )
+        })
+        .collect::<Map<String, Value>>()
+        .into()
+}
+
+/// Characters in a string, elements in an array, entries in an object, the
+/// absolute value of a number, and 0 for `null`.
+fn length(input: &Value, _: &[Value]) -> Result<Value> {
+    Ok(match input {
+        Value::Null => Value::from(0),
+        Value::String(string) => Value::from(string.chars().count()),
+        Value::Array(array) => Value::from(array.len()),
+        Value::Object(object) => Value::from(object.len()),
+        Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(i), _) if i != i64::MIN => Value::from(i.abs()),
+            (_, Some(u)) => Value::from(u),
+            _ => Value::from(number.as_f64().unwrap().abs()),
+        },
+        Value::Bool(_) => {
+            return Err(YajqError::Type(format!(
+                "{} has no length",
+                describe(input)
+            )))
+        }
+    })
+}
+
+/// The sorted keys of an object, or the indices of an array.
+fn keys(input: &Value, _: &[Value]) -> Result<Value> {
+    match input {
+        Value::Object(object) => Ok(object.keys().cloned().map(Value::from).collect()),
+        Value::Array(array) => Ok((0..array.len()).map(Value::from).collect()),
+        _ => Err(YajqError::Type(format!("{} has no keys", describe(input)))),
+    }
+}
+
+/// The values of an object in key order, or the elements of an array.
+fn values(input: &Value, _: &[Value]) -> Result<Value> {
+    match input {
+        Value::Object(object) => Ok(object.values().cloned().collect()),
+        Value::Array(array) => Ok(Value::Array(array.to_owned())),
+        _ => Err(YajqError::Type(format!(
+            "{} has no values",
+            describe(input)
+        ))),
+    }
+}
+
+/// The elements of an array or the characters of a string in reverse order;
+/// `null` reverses to an empty array.
+fn reverse(input: &Value, _: &[Value]) -> Result<Value> {
+    match input {
+        Value::Null => Ok(Value::Array(vec![])),
+        Value::String(string) => Ok(Value::from(string.chars().rev().collect::<String>())),
+        Value::Array(array) => Ok(array.iter().rev().cloned().collect()),
+        _ => Err(YajqError::Type(format!(
+            "{} cannot be reversed",
+            describe(input)
+        ))),
+    }
+}
+
+/// The middle number of an array, or the mean of the middle two; `null` for
+/// an empty array.
+fn median(input: &Value, _: &[Value]) -> Result<Value> {
+    let mut numbers = numbers(input, "median")?;
+    numbers.sort_by(|left, right| compare_numbers(left, right));
+    let middle = numbers.len() / 2;
+    match numbers.len() {
+        0 => Ok(Value::Null),
+        length if length % 2 == 1 => Ok(Value::Number(numbers[middle].to_owned())),
+        _ => arithmetic(
+            BinaryOp::Div,
+            &sum(&numbers[middle - 1..=middle])?,
+            &Value::from(2),
+        ),
+    }
+}
+
+/// `percentile(p)`: the `p`th percentile of an array of numbers, for `p`
+/// from 0 to 100, interpolating linearly between the closest ranks.
+fn percentile(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let p = match arguments[0].as_f64() {
+        Some(p) if (0.0..=100.0).contains(&p) => p,
+        _ => {
+            return Err(YajqError::Type(format!(
+                "percentile needs a number from 0 to 100, not {}",
+                describe(&arguments[0])
+            )))
+        }
+    };
+    let floats = sorted_floats(input, "percentile")?;
+    if floats.is_empty() {
+        return Ok(Value::Null);
+    }
+    let rank = p / 100.0 * (floats.len() - 1) as f64;
+    let (lower, upper) = (floats[rank.floor() as usize], floats[rank.ceil() as usize]);
+    statistic(lower + (upper - lower) * rank.fract())
+}
+
+/// The population standard deviation of an array of numbers.
+fn stddev(input: &Value, _: &[Value]) -> Result<Value> {
+    let floats = sorted_floats(input, "stddev")?;
+    if floats.is_empty() {
+        return Ok(Value::Null);
+    }
+    let count = floats.len() as f64;
+    let mean = floats.iter().sum::<f64>() / count;
+    let variance = floats.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / count;
+    statistic(variance.sqrt())
+}
+
+/// The most buckets `histogram` will split a range into.
+const MAX_BUCKETS: u64 = 10_000;
+
+/// `histogram(buckets)`: splits the range of an array of numbers into
+/// `buckets` equal parts, outputting `{from, to, count}` for each; the last
+/// part includes its upper bound.
+fn histogram(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let buckets = match arguments[0].as_u64() {
+        Some(buckets) if buckets > MAX_BUCKETS => {
+            return Err(YajqError::Type(format!(
+                "histogram allows at most {} buckets, not {}",
+                MAX_BUCKETS, buckets
+            )))
+        }
+        Some(buckets) if buckets > 0 => buckets as usize,
+        _ => {
+            return Err(YajqError::Type(format!(
+                "histogram needs a positive number of buckets, not {}",
+                describe(&arguments[0])
+            )))
+        }
+    };
+    let floats = sorted_floats(input, "histogram")?;
+    let (min, max) = match (floats.first(), floats.last()) {
+        (Some(min), Some(max)) => (*min, *max),
+        _ => return Ok(Value::Array(vec![])),
+    };
+    let width = (max - min) / buckets as f64;
+    let mut counts = vec![0; buckets];
+    for x in &floats {
+        let bucket = if width == 0.0 {
+            0
+        } else {
+            (((x - min) / width) as usize).min(buckets - 1)
+        };
+        counts[bucket] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(bucket, count)| {
+            let to = if bucket == buckets - 1 {
+                max
+            } else {
+                min + width * (bucket + 1) as f64
+            };
+            Ok(json!({
+                "from": statistic(min + width * bucket as f64)?,
+                "to": statistic(to)?,
+                "count": count,
+            }))
+        })
+        .collect()
+}
+
+/// The string a string function is applied to or given.
+pub fn text<'a>(value: &'a Value, name: &str) -> Result<&'a str> {
+    match value {
+        Value::String(string) => Ok(string),
+        _ => Err(YajqError::Type(format!(
+            "{} needs a string, not {}",
+            name,
+            describe(value)
+        ))),
+    }
+}
+
+/// `split(separator)`: the parts of a string between the separators, or its
+/// characters if the separator is empty.
+fn split(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let separator = text(&arguments[0], "split")?;
+    let string = text(input, "split")?;
+    if string.is_empty() {
+        return Ok(Value::Array(vec![]));
+    }
+    Ok(if separator.is_empty() {
+        string.chars().map(|c| Value::from(c.to_string())).collect()
+    } else {
+        string.split(separator).map(Value::from).collect()
+    })
+}
+
+/// `join(separator)`: the elements of an array joined into a string. Numbers
+/// and booleans are joined as JSON, and `null` as an empty string.
+fn join(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let separator = text(&arguments[0], "join")?;
+    let parts = array(input, "join")?
+        .iter()
+        .map(|element| match element {
+            Value::String(string) => Ok(string.to_owned()),
+            Value::Null => Ok(String::new()),
+            Value::Number(_) | Value::Bool(_) => Ok(element.to_string()),
+            _ => Err(YajqError::Type(format!(
+                "Cannot join {}",
+                describe(element)
+            ))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Value::from(parts.join(separator)))
+}
+
+/// Whether `container` contains `value`: strings contain their substrings,
+/// arrays contain arrays whose every element is contained in one of theirs,
+/// and objects contain objects whose every value is contained in theirs under
+/// the same key. Other values only contain equal ones.
+fn contains(container: &Value, value: &Value) -> Result<bool> {
+    match (container, value) {
+        (Value::String(container), Value::String(value)) => Ok(container.contains(value.as_str())),
+        (Value::Array(container), Value::Array(values)) => {
+            // Elements of other types simply don't match.
+            Ok(values.iter().all(|value| {
+                container
+                    .iter()
+                    .any(|element| matches!(contains(element, value), Ok(true)))
+            }))
+        }
+        (Value::Object(container), Value::Object(entries)) => {
+            for (key, value) in entries {
+                match container.get(key) {
+                    Some(element) if contains(element, value)? => {}
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        _ if type_name(container) == type_name(value) => {
+            Ok(compare(container, value) == Ordering::Equal)
+        }
+        _ => Err(YajqError::Type(format!(
+            "{} cannot contain {}",
+            describe(container),
+            describe(value)
+        ))),
+    }
+}
+
+/// The widest string `pad` will produce.
+const MAX_WIDTH: u64 = 1 << 20;
+
+/// `pad(width)` or `pad(width; fill)`: a string padded with spaces, or the
+/// `fill` character, to `width` characters; on the left for a positive width
+/// and on the right for a negative one, like `printf`'s `%5s` and `%-5s`.
+/// Values other than strings are padded as JSON.
+fn pad(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let width = arguments[0].as_i64().ok_or_else(|| {
+        YajqError::Type(format!(
+            "pad needs an integer width, not {}",
+            describe(&arguments[0])
+        ))
+    })?;
+    if width.unsigned_abs() > MAX_WIDTH {
+        return Err(YajqError::Type(format!(
+            "pad allows a width of at most {}, not {}",
+            MAX_WIDTH, width
+        )));
+    }
+    let fill = match arguments.get(1) {
+        None => ' ',
+        Some(fill) => {
+            let mut chars = text(fill, "pad")?.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => {
+                    return Err(YajqError::Type(format!(
+                        "pad needs a single fill character, not {}",
+                        describe(fill)
+                    )))
+                }
+            }
+        }
+    };
+    let string = match input {
+        Value::String(string) => string.to_owned(),
+        _ => input.to_string(),
+    };
+    let missing = (width.unsigned_abs() as usize).saturating_sub(string.chars().count());
+    let padding: String = std::iter::repeat_n(fill, missing).collect();
+    Ok(Value::from(if width < 0 {
+        string + &padding
+    } else {
+        padding + &string
+    }))
+}
+
+/// `substr(start)` or `substr(start; length)`: the characters of a string
+/// from `start`, counting from the end if it's negative, up to `length` of
+/// them.
+fn substr(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let chars: Vec<char> = text(input, "substr")?.chars().collect();
+    let integer = |value: &Value| {
+        value.as_i64().ok_or_else(|| {
+            YajqError::Type(format!(
+                "substr needs integer arguments, not {}",
+                describe(value)
+            ))
+        })
+    };
+    let start = integer(&arguments[0])?;
+    let start = if start < 0 {
+        chars.len().saturating_sub(start.unsigned_abs() as usize)
+    } else {
+        (start as usize).min(chars.len())
+    };
+    let end = match arguments.get(1) {
+        Some(length) => start + (integer(length)?.max(0) as usize).min(chars.len() - start),
+        None => chars.len(),
+    };
+    Ok(Value::from(chars[start..end].iter().collect::<String>()))
+}
+
+/// Whether an object has the given key, or an array the given index.
+fn has(input: &Value, arguments: &[Value]) -> Result<Value> {
+    match (input, &arguments[0]) {
+        (Value::Object(object), Value::String(key)) => Ok(Value::Bool(object.contains_key(key))),
+        (Value::Array(array), Value::Number(index)) => Ok(Value::Bool(
+            index
+                .as_u64()
+                .is_some_and(|index| index < array.len() as u64),
+        )),
+        (_, key) => Err(YajqError::Type(format!(
+            "Cannot check whether {} has key {}",
+            describe(input),
+            describe(key)
+        ))),
+    }
+}
+
+/// Runs `expr` against `data` with the default options, for the tests of
+/// built-ins here and in `patterns`.
+#[cfg(test)]
+pub fn call_(data: Value, expr: &str) -> Result<Vec<Value>> {
+    crate::filter::filter(
+        &data,
+        &crate::parser::parse_expression(expr).unwrap(),
+        &Options::default(),
+        &Scope::default(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length() {
+        let data = json!({"name": "Zoë", "tags": [1, 2, 3], "n": -4, "none": null});
+        assert_eq!(call_(data.clone(), "length").unwrap(), vec![json!(4)]);
+        assert_eq!(
+            call_(data.clone(), "name, tags, n, none | length").unwrap(),
+            vec![json!(3), json!(3), json!(4), json!(0)]
+        );
+        assert_eq!(
+            call_(json!(true), "length").unwrap_err().to_string(),
+            "Type Error: boolean (true) has no length"
+        );
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let data = json!({"b": 1, "a": [true, null]});
+        assert_eq!(
+            call_(data.clone(), "keys").unwrap(),
+            vec![json!(["a", "b"])]
+        );
+        assert_eq!(
+            call_(data.clone(), "a | keys").unwrap(),
+            vec![json!([0, 1])]
+        );
+        assert_eq!(
+            call_(data.clone(), "values").unwrap(),
+            vec![json!([[true, null], 1])]
+        );
+        assert_eq!(
+            call_(data, "b | keys").unwrap_err().to_string(),
+            "Type Error: number (1) has no keys"
+        );
+    }
+
+    #[test]
+    fn test_type() {
+        assert_eq!(
+            call_(json!([null, true, 1.5, "a", [], {}]), ".[] | type").unwrap(),
+            vec![
+                json!("null"),
+                json!("boolean"),
+                json!("number"),
+                json!("string"),
+                json!("array"),
+                json!("object")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_has() {
+        let data = json!({"a": [1, 2]});
+        assert_eq!(
+            call_(data.clone(), r#"has("a"), has("b"), (.a | has(1), has(2))"#).unwrap(),
+            vec![json!(true), json!(false), json!(true), json!(false)]
+        );
+        assert_eq!(
+            call_(data.clone(), r#"has("a", "b")"#).unwrap(),
+            vec![json!(true), json!(false)]
+        );
+        assert_eq!(
+            call_(data, "has(0)").unwrap_err().to_string(),
+            r#"Type Error: Cannot check whether object ({"a":[1,2]}) has key number (0)"#
+        );
+    }
+
+    #[test]
+    fn test_not_and_empty() {
+        assert_eq!(
+            call_(json!([null, 0, false]), ".[] | not").unwrap(),
+            vec![json!(true), json!(false), json!(true)]
+        );
+        assert_eq!(call_(json!(1), "empty").unwrap(), Vec::<Value>::new());
+        assert_eq!(
+            call_(json!(1), "1, empty, 2").unwrap(),
+            vec![json!(1), json!(2)]
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        assert_eq!(
+            call_(json!(1), r#"error("bad input")"#)
+                .unwrap_err()
+                .to_string(),
+            "Error: bad input"
+        );
+        assert_eq!(
+            call_(json!({"code": 1}), "error").unwrap_err().to_string(),
+            r#"Error: {"code":1} (not a string)"#
+        );
+    }
+
+    #[test]
+    fn test_map_and_select() {
+        let data = json!({"people": [
+            {"name": "Adam", "email": "adam@x.com"},
+            {"name": "Eve", "email": "eve@x.com"},
+            {"name": "Ezra"}
+        ]});
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"people | select(.name starts_with "E") | map(.name)"#
+            )
+            .unwrap(),
+            vec![json!(["Eve", "Ezra"])]
+        );
+        assert_eq!(
+            call_(data.clone(), "people | select(.email) | map(.email)").unwrap(),
+            vec![json!(["adam@x.com", "eve@x.com"])]
+        );
+        // An array's elements are selected, never the array itself.
+        assert_eq!(
+            call_(json!([[1, 2], {"a": 1}, [3]]), ".[] | select(. != 1)").unwrap(),
+            vec![json!([2]), json!({"a": 1}), json!([3])]
+        );
+        assert_eq!(
+            call_(
+                json!([[1, 2], {"a": 1}, []]),
+                r#".[] | if type == "array" then . else empty end"#
+            )
+            .unwrap(),
+            vec![json!([1, 2]), json!([])]
+        );
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"people[] | select(.name == "Eve") | .email"#
+            )
+            .unwrap(),
+            vec![json!("eve@x.com")]
+        );
+        assert_eq!(
+            call_(data.clone(), "people | map(.name, .email?)").unwrap(),
+            vec![json!([
+                "Adam",
+                "adam@x.com",
+                "Eve",
+                "eve@x.com",
+                "Ezra",
+                null
+            ])]
+        );
+        assert_eq!(
+            call_(data, "people | map(.email)").unwrap_err().to_string(),
+            "Filtering Error: Key email not in dict"
+        );
+        assert_eq!(
+            call_(json!({"a": 1, "b": 2}), "map(. == 2)").unwrap(),
+            vec![json!([false, true])]
+        );
+        assert_eq!(
+            call_(json!(3), "map(.)").unwrap_err().to_string(),
+            "Type Error: map needs an array or object, not number (3)"
+        );
+    }
+
+    #[test]
+    fn test_flat_map() {
+        assert_eq!(
+            call_(
+                json!([{"tags": ["a", "b"]}, {"tags": []}, {"tags": "c"}]),
+                "flat_map(.tags)"
+            )
+            .unwrap(),
+            vec![json!(["a", "b", "c"])]
+        );
+    }
+
+    #[test]
+    fn test_any_and_all() {
+        let data = json!([{"age": 20}, {"age": 40}, {}]);
+        assert_eq!(
+            call_(
+                data.clone(),
+                "any(.age > 30), all(.age > 30), any(.age > 50)"
+            )
+            .unwrap(),
+            vec![json!(true), json!(false), json!(false)]
+        );
+        assert_eq!(
+            call_(json!([]), "any(.), all(.)").unwrap(),
+            vec![json!(false), json!(true)]
+        );
+    }
+
+    #[test]
+    fn test_sort_and_unique() {
+        let data = json!([{"a": 1}, "b", [2], 3, true, null, false, "a", [1, 2], 1.0, 3]);
+        assert_eq!(
+            call_(data.clone(), "sort").unwrap(),
+            vec![json!([null, false, true, 1.0, 3, 3, "a", "b", [1, 2], [2], {"a": 1}])]
+        );
+        assert_eq!(
+            call_(data, "unique").unwrap(),
+            vec![json!([null, false, true, 1.0, 3, "a", "b", [1, 2], [2], {"a": 1}])]
+        );
+        assert_eq!(
+            call_(json!({"a": 1}), "sort").unwrap_err().to_string(),
+            r#"Type Error: sort needs an array, not object ({"a":1})"#
+        );
+    }
+
+    #[test]
+    fn test_ordering_by_key() {
+        let data = json!([
+            {"name": "c", "team": 2},
+            {"name": "a", "team": 1},
+            {"name": "b", "team": 2},
+            {"name": "d", "team": 1}
+        ]);
+        assert_eq!(
+            call_(data.clone(), "sort_by(.team) | map(.name)").unwrap(),
+            vec![json!(["a", "d", "c", "b"])]
+        );
+        assert_eq!(
+            call_(data.clone(), "sort_by(.team, .name) | map(.name)").unwrap(),
+            vec![json!(["a", "d", "b", "c"])]
+        );
+        assert_eq!(
+            call_(data.clone(), "group_by(.team) | map(map(.name))").unwrap(),
+            vec![json!([["a", "d"], ["c", "b"]])]
+        );
+        assert_eq!(
+            call_(data.clone(), "unique_by(.team) | map(.name)").unwrap(),
+            vec![json!(["a", "c"])]
+        );
+        // Ties go to the first minimum and the last maximum.
+        assert_eq!(
+            call_(data.clone(), "min_by(.team).name, max_by(.team).name").unwrap(),
+            vec![json!("a"), json!("b")]
+        );
+        assert_eq!(
+            call_(json!([]), "min_by(.a), max_by(.a)").unwrap(),
+            vec![json!(null), json!(null)]
+        );
+        assert!(call_(json!({"a": 1}), "sort_by(.)").is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(
+            call_(json!([1, [2, 3], 4]), "reverse").unwrap(),
+            vec![json!([4, [2, 3], 1])]
+        );
+        assert_eq!(
+            call_(json!({"s": "Zoë", "n": null}), "s, n | reverse").unwrap(),
+            vec![json!("ëoZ"), json!([])]
+        );
+        assert_eq!(
+            call_(json!(1), "reverse").unwrap_err().to_string(),
+            "Type Error: number (1) cannot be reversed"
+        );
+    }
+
+    #[test]
+    fn test_add_and_sum() {
+        assert_eq!(
+            call_(json!([[1, 2], [3]]), "add").unwrap(),
+            vec![json!([1, 2, 3])]
+        );
+        assert_eq!(
+            call_(json!({"a": "x", "b": "y"}), "add").unwrap(),
+            vec![json!("xy")]
+        );
+        assert_eq!(
+            call_(json!([]), "add, sum").unwrap(),
+            vec![json!(null), json!(0)]
+        );
+        assert_eq!(call_(json!([1, 2, 0.5]), "sum").unwrap(), vec![json!(3.5)]);
+        assert_eq!(
+            call_(json!([9007199254740993i64, 2]), "sum").unwrap(),
+            vec![json!(9007199254740995i64)]
+        );
+        assert_eq!(
+            call_(json!([1, "2"]), "sum").unwrap_err().to_string(),
+            r#"Type Error: sum needs numbers, not string ("2")"#
+        );
+    }
+
+    #[test]
+    fn test_summaries() {
+        let data = json!([3, 1, 4, 1, 5]);
+        assert_eq!(
+            call_(data.clone(), "avg, min, max, count, median").unwrap(),
+            vec![json!(2.8), json!(1), json!(5), json!(5), json!(3)]
+        );
+        assert_eq!(
+            call_(json!([4, 2]), "avg, median").unwrap(),
+            vec![json!(3), json!(3)]
+        );
+        assert_eq!(call_(json!([1, 2]), "median").unwrap(), vec![json!(1.5)]);
+        assert_eq!(
+            call_(json!([]), "avg, min, max, count, median").unwrap(),
+            vec![json!(null), json!(null), json!(null), json!(0), json!(null)]
+        );
+        assert_eq!(
+            call_(json!(["b", null, "a"]), "min, max").unwrap(),
+            vec![json!(null), json!("b")]
+        );
+    }
+
+    #[test]
+    fn test_distribution() {
+        let data = json!([15, 20, 35, 40, 50]);
+        assert_eq!(
+            call_(
+                data.clone(),
+                "percentile(0), percentile(40), percentile(90), percentile(100)"
+            )
+            .unwrap(),
+            vec![json!(15), json!(29), json!(46), json!(50)]
+        );
+        assert_eq!(
+            call_(data.clone(), "percentile(101)")
+                .unwrap_err()
+                .to_string(),
+            "Type Error: percentile needs a number from 0 to 100, not number (101)"
+        );
+        assert_eq!(
+            call_(json!([2, 4, 4, 4, 5, 5, 7, 9]), "stddev").unwrap(),
+            vec![json!(2)]
+        );
+        assert_eq!(call_(json!([1, 2]), "stddev").unwrap(), vec![json!(0.5)]);
+        assert_eq!(
+            call_(json!([1, 2, 2, 9, 10]), "histogram(3)").unwrap(),
+            vec![json!([
+                {"from": 1, "to": 4, "count": 3},
+                {"from": 4, "to": 7, "count": 0},
+                {"from": 7, "to": 10, "count": 2}
+            ])]
+        );
+        assert_eq!(
+            call_(json!([3, 3]), "histogram(2)").unwrap(),
+            vec![json!([
+                {"from": 3, "to": 3, "count": 2},
+                {"from": 3, "to": 3, "count": 0}
+            ])]
+        );
+        assert_eq!(
+            call_(json!([]), "histogram(2), stddev, percentile(50)").unwrap(),
+            vec![json!([]), json!(null), json!(null)]
+        );
+        assert!(call_(data, "histogram(0)").is_err());
+        assert_eq!(
+            call_(json!([1]), "histogram(100000000000000)")
+                .unwrap_err()
+                .to_string(),
+            "Type Error: histogram allows at most 10000 buckets, not 100000000000000"
+        );
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        assert_eq!(
+            call_(json!("a, b, c"), r#"split(", ")"#).unwrap(),
+            vec![json!(["a", "b", "c"])]
+        );
+        assert_eq!(
+            call_(json!("Zoë"), r#"split(""), ("" | split(","))"#).unwrap(),
+            vec![json!(["Z", "o", "ë"]), json!([])]
+        );
+        assert_eq!(
+            call_(json!(["a", 1, null, true]), r#"join("-")"#).unwrap(),
+            vec![json!("a-1--true")]
+        );
+        assert_eq!(
+            call_(json!([[1]]), r#"join("-")"#).unwrap_err().to_string(),
+            "Type Error: Cannot join array ([1])"
+        );
+        assert_eq!(
+            call_(json!(1), r#"split(",")"#).unwrap_err().to_string(),
+            "Type Error: split needs a string, not number (1)"
+        );
+    }
+
+    #[test]
+    fn test_case_and_trimming() {
+        let data = json!({"s": "  Zoë Smith\t"});
+        assert_eq!(
+            call_(
+                data.clone(),
+                "s | ascii_downcase, ascii_upcase, trim, ltrim, rtrim"
+            )
+            .unwrap(),
+            vec![
+                json!("  zoë smith\t"),
+                json!("  ZOë SMITH\t"),
+                json!("Zoë Smith"),
+                json!("Zoë Smith\t"),
+                json!("  Zoë Smith")
+            ]
+        );
+        assert_eq!(
+            call_(
+                json!("prefix-name.json"),
+                r#"ltrimstr("prefix-"), rtrimstr(".json"), rtrimstr("x"), (1 | ltrimstr("1"))"#
+            )
+            .unwrap(),
+            vec![
+                json!("name.json"),
+                json!("prefix-name"),
+                json!("prefix-name.json"),
+                json!(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string_predicates() {
+        assert_eq!(
+            call_(
+                json!("foobar"),
+                r#"startswith("foo"), endswith("foo"), contains("oba"), contains("x")"#
+            )
+            .unwrap(),
+            vec![json!(true), json!(false), json!(true), json!(false)]
+        );
+        let data = json!({"tags": ["red", "green"], "size": {"w": 1, "h": 2}});
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"contains({tags: ["ree"]}), contains({size: {w: 1}}), contains({size: {w: 2}})"#
+            )
+            .unwrap(),
+            vec![json!(true), json!(true), json!(false)]
+        );
+        assert_eq!(
+            call_(json!([1, "a"]), r#"contains([1]), contains(["b"])"#).unwrap(),
+            vec![json!(true), json!(false)]
+        );
+        assert_eq!(
+            call_(json!("a"), "contains(1)").unwrap_err().to_string(),
+            r#"Type Error: string ("a") cannot contain number (1)"#
+        );
+        assert!(call_(json!(1), r#"startswith("1")"#).is_err());
+    }
+
+    #[test]
+    fn test_replace_pad_and_substr() {
+        assert_eq!(
+            call_(json!("a.b.c"), r#"replace("."; "::")"#).unwrap(),
+            vec![json!("a::b::c")]
+        );
+        assert_eq!(
+            call_(
+                json!({"s": "ab", "n": 42}),
+                r#"(s | pad(4), pad(-4), pad(1)), (n | pad(5; "0"))"#
+            )
+            .unwrap(),
+            vec![json!("  ab"), json!("ab  "), json!("ab"), json!("00042")]
+        );
+        assert!(call_(json!("a"), r#"pad(3; "ab")"#).is_err());
+        assert_eq!(
+            call_(json!("a"), "pad(-100000000000000)")
+                .unwrap_err()
+                .to_string(),
+            "Type Error: pad allows a width of at most 1048576, not -100000000000000"
+        );
+        assert_eq!(
+            call_(
+                json!("Zoë Smith"),
+                "substr(4), substr(0; 3), substr(-5; 2), substr(2; 100), substr(20)"
+            )
+            .unwrap(),
+            vec![
+                json!("Smith"),
+                json!("Zoë"),
+                json!("Sm"),
+                json!("ë Smith"),
+                json!("")
+            ]
+        );
+        assert_eq!(call_(json!("Zoë"), "length").unwrap(), vec![json!(3)]);
+    }
+
+    #[test]
+    fn test_environment() {
+        env::set_var("YAJQ_TEST_SERVICE", "db");
+        let data = json!({"services": {"db": {"port": 5432}}});
+        assert_eq!(
+            call_(data.clone(), "services[$ENV.YAJQ_TEST_SERVICE].port").unwrap(),
+            vec![json!(5432)]
+        );
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"env("YAJQ_TEST_SERVICE"), env("YAJQ_TEST_UNSET")"#
+            )
+            .unwrap(),
+            vec![json!("db"), json!(null)]
+        );
+        assert_eq!(
+            call_(data, r#"{} as $ENV | $ENV"#).unwrap(),
+            vec![json!({})]
+        );
+    }
+
+    #[test]
+    fn test_arity() {
+        // Built-ins are looked up by name and arity, so a bare name that is
+        // only built in with arguments is still a key.
+        assert_eq!(
+            call_(json!({"has": 1, "split": 2}), "has, split").unwrap(),
+            vec![json!(1), json!(2)]
+        );
+    }
+}
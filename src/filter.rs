@@ -0,0 +1,1458 @@
+use crate::ast::{BinaryOp, Expr, Token, UnaryOp};
+use crate::builtins::{self, Builtin};
+use crate::scope::{Function, Scope};
+use crate::value::{arithmetic, compare, describe, negate};
+use crate::{Result, YajqError};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+/// Settings that change how expressions are evaluated.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// How many levels below the current value `..` searches; unlimited if unset
+    pub max_depth: Option<usize>,
+    /// Missing keys and out of range indexes evaluate to `null`, or are left
+    /// out of `*` results, instead of failing
+    pub lenient: bool,
+    /// Wildcards other than `[]` gather their results into a single array
+    /// instead of outputting them one by one
+    pub collect: bool,
+}
+
+/// Evaluates `expr` against `data`, returning every value it outputs.
+pub fn filter(data: &Value, expr: &Expr, options: &Options, scope: &Scope) -> Result<Vec<Value>> {
+    let mut outputs = Vec::new();
+    eval(expr, data, options, scope, &mut |value| {
+        outputs.push(value);
+        Ok(())
+    })?;
+    Ok(outputs)
+}
+
+/// Evaluates `expr` against `input`, passing each output to `out` as soon as
+/// it is produced.
+pub fn eval(
+    expr: &Expr,
+    input: &Value,
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    match expr {
+        Expr::Identity => out(input.to_owned()),
+        Expr::Literal(value) => out(value.to_owned()),
+        Expr::Variable(name) => match scope.get(name) {
+            Some(value) => out(value.to_owned()),
+            // `$ENV` is the process environment unless a binding shadows it.
+            None if name == "ENV" => out(builtins::environment()),
+            None => Err(YajqError::Filtering(format!(
+                "Variable ${} is not defined",
+                name
+            ))),
+        },
+        Expr::Bind(source, name, body) => eval(source, input, options, scope, &mut |value| {
+            eval(body, input, options, &scope.bind(name, value), out)
+        }),
+        Expr::Path(base, tokens) => {
+            let keys = dynamic_keys(tokens);
+            eval(base, input, options, scope, &mut |value| {
+                if keys.is_empty() {
+                    return walk(&value, tokens, String::new(), false, options, scope, out);
+                }
+                combinations(&keys, input, options, scope, &mut Vec::new(), &mut |keys| {
+                    let tokens = resolve(tokens, &mut keys.iter())?;
+                    walk(&value, &tokens, String::new(), false, options, scope, out)
+                })
+            })
+        }
+        Expr::Pipe(left, right) => eval(left, input, options, scope, &mut |value| {
+            eval(right, &value, options, scope, out)
+        }),
+        Expr::Comma(left, right) => {
+            eval(left, input, options, scope, out)?;
+            eval(right, input, options, scope, out)
+        }
+        Expr::Array(expr) => out(Value::Array(filter(input, expr, options, scope)?)),
+        Expr::Object(entries) => construct(entries, input, options, scope, Map::new(), out),
+        Expr::If(condition, then, otherwise) => {
+            if holds(condition, input, options, scope)? {
+                eval(then, input, options, scope, out)
+            } else {
+                eval(otherwise, input, options, scope, out)
+            }
+        }
+        Expr::Reduce(source, name, init, update) => {
+            eval(init, input, options, scope, &mut |mut accumulator| {
+                eval(source, input, options, scope, &mut |value| {
+                    // The last output of the update becomes the accumulator.
+                    let scope = scope.bind(name, value);
+                    let mut last = Value::Null;
+                    eval(update, &accumulator, options, &scope, &mut |value| {
+                        last = value;
+                        Ok(())
+                    })?;
+                    accumulator = last;
+                    Ok(())
+                })?;
+                out(accumulator)
+            })
+        }
+        Expr::Foreach(source, name, init, update, extract) => {
+            eval(init, input, options, scope, &mut |mut accumulator| {
+                eval(source, input, options, scope, &mut |value| {
+                    let scope = scope.bind(name, value);
+                    let mut updates = Vec::new();
+                    eval(update, &accumulator, options, &scope, &mut |value| {
+                        updates.push(value);
+                        Ok(())
+                    })?;
+                    for value in updates {
+                        match extract {
+                            Some(extract) => eval(extract, &value, options, &scope, out)?,
+                            None => out(value.clone())?,
+                        }
+                        accumulator = value;
+                    }
+                    Ok(())
+                })?;
+                Ok(())
+            })
+        }
+        Expr::Unary(UnaryOp::Not, operand) => eval(operand, input, options, scope, &mut |value| {
+            out(Value::Bool(!truthy(&value)))
+        }),
+        Expr::Unary(UnaryOp::Neg, operand) => eval(operand, input, options, scope, &mut |value| {
+            out(negate(&value)?)
+        }),
+        Expr::Binary(BinaryOp::And, left, right) => {
+            eval(left, input, options, scope, &mut |left| {
+                if !truthy(&left) {
+                    return out(Value::Bool(false));
+                }
+                eval(right, input, options, scope, &mut |right| {
+                    out(Value::Bool(truthy(&right)))
+                })
+            })
+        }
+        Expr::Binary(BinaryOp::Or, left, right) => eval(left, input, options, scope, &mut |left| {
+            if truthy(&left) {
+                return out(Value::Bool(true));
+            }
+            eval(right, input, options, scope, &mut |right| {
+                out(Value::Bool(truthy(&right)))
+            })
+        }),
+        Expr::Binary(BinaryOp::Alternative, left, right) => {
+            // A missing key on the left falls back just like `null` does.
+            let mut found = false;
+            eval(left, input, &lenient(options), scope, &mut |value| {
+                if !truthy(&value) {
+                    return Ok(());
+                }
+                found = true;
+                out(value)
+            })?;
+            if found {
+                Ok(())
+            } else {
+                eval(right, input, options, scope, out)
+            }
+        }
+        Expr::Binary(op, left, right) => eval(left, input, options, scope, &mut |left| {
+            eval(right, input, options, scope, &mut |right| {
+                out(binary(*op, &left, &right)?)
+            })
+        }),
+        Expr::Def(definition, rest) => {
+            eval(rest, input, options, &scope.define(definition.clone()), out)
+        }
+        Expr::Template(parts) => combinations(
+            parts,
+            input,
+            options,
+            scope,
+            &mut Vec::new(),
+            &mut |values| {
+                let text: String = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(string) => string.to_owned(),
+                        value => value.to_string(),
+                    })
+                    .collect();
+                out(Value::from(text))
+            },
+        ),
+        Expr::Import(module, alias, rest) => {
+            let imported = scope.import(alias, &module_scope(module, Scope::default()));
+            eval(rest, input, options, &imported, out)
+        }
+        Expr::Call(name, arguments) => match scope.function(name, arguments.len()) {
+            Some(Function::Defined(definition, defined)) => {
+                // Arguments are closures over the caller's scope.
+                let mut body = defined;
+                for (param, argument) in definition.params.iter().zip(arguments) {
+                    body = body.bind_closure(param, argument.clone(), scope.clone());
+                }
+                eval(&definition.body, input, options, &body, out)
+            }
+            Some(Function::Closure(expr, closure)) => eval(expr, input, options, closure, out),
+            None => call(name, arguments, input, options, scope, out),
+        },
+    }
+}
+
+/// `scope` with the definitions and imports of `module` added. Modules only
+/// see their own functions, so they are loaded into an empty scope.
+fn module_scope(module: &Expr, scope: Scope) -> Scope {
+    match module {
+        Expr::Def(definition, rest) => module_scope(rest, scope.define(definition.clone())),
+        Expr::Import(imported, alias, rest) => {
+            let scope = scope.import(alias, &module_scope(imported, Scope::default()));
+            module_scope(rest, scope)
+        }
+        _ => scope,
+    }
+}
+
+/// Calls the built-in function `name`.
+fn call(
+    name: &str,
+    arguments: &[Expr],
+    input: &Value,
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    match builtins::lookup(name, arguments.len()) {
+        Some(Builtin::Native(function)) => combinations(
+            arguments,
+            input,
+            options,
+            scope,
+            &mut Vec::new(),
+            &mut |values| out(function(input, values)?),
+        ),
+        Some(Builtin::Generator(function)) => function(input, arguments, options, scope, out),
+        None => Err(YajqError::Filtering(format!(
+            "Unknown function {}/{}",
+            name,
+            arguments.len()
+        ))),
+    }
+}
+
+/// The expressions of the `[expr]` tokens in `tokens`.
+fn dynamic_keys(tokens: &[Token]) -> Vec<Expr> {
+    tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Dynamic(expr) => Some((**expr).clone()),
+            Token::Optional(token) => match &**token {
+                Token::Dynamic(expr) => Some((**expr).clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replaces each `[expr]` token in `tokens` with the key or index given by
+/// the next of `keys`.
+fn resolve<'a>(tokens: &[Token], keys: &mut impl Iterator<Item = &'a Value>) -> Result<Vec<Token>> {
+    tokens
+        .iter()
+        .map(|token| match token {
+            Token::Dynamic(_) => match keys.next().unwrap() {
+                Value::String(key) => Ok(Token::Key(key.clone())),
+                Value::Number(index) if index.is_i64() => Ok(Token::Index(index.as_i64().unwrap())),
+                key => Err(YajqError::Type(format!(
+                    "Cannot index with {}",
+                    describe(key)
+                ))),
+            },
+            Token::Optional(token) => Ok(Token::Optional(Box::new(
+                resolve(std::slice::from_ref(&**token), keys)?.remove(0),
+            ))),
+            token => Ok(token.clone()),
+        })
+        .collect()
+}
+
+/// Outputs `object` extended with every combination of the outputs of the
+/// keys and values in `entries`.
+fn construct(
+    entries: &[(Expr, Expr)],
+    input: &Value,
+    options: &Options,
+    scope: &Scope,
+    object: Map<String, Value>,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let ((key, value), rest) = match entries.split_first() {
+        None => return out(Value::Object(object)),
+        Some(split) => split,
+    };
+    eval(key, input, options, scope, &mut |key| {
+        let key = match key {
+            Value::String(key) => key,
+            key => {
+                return Err(YajqError::Type(format!(
+                    "Object keys must be strings, not {}",
+                    describe(&key)
+                )))
+            }
+        };
+        eval(value, input, options, scope, &mut |value| {
+            let mut object = object.clone();
+            object.insert(key.clone(), value);
+            construct(rest, input, options, scope, object, out)
+        })
+    })
+}
+
+/// Calls `f` with every combination of the outputs of `arguments`, the last
+/// argument varying fastest.
+pub fn combinations(
+    arguments: &[Expr],
+    input: &Value,
+    options: &Options,
+    scope: &Scope,
+    values: &mut Vec<Value>,
+    f: &mut dyn FnMut(&[Value]) -> Result<()>,
+) -> Result<()> {
+    let (argument, rest) = match arguments.split_first() {
+        None => return f(values),
+        Some(split) => split,
+    };
+    eval(argument, input, options, scope, &mut |value| {
+        values.push(value);
+        let result = combinations(rest, input, options, scope, values, f);
+        values.pop();
+        result
+    })
+}
+
+fn binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value> {
+    Ok(Value::Bool(match op {
+        BinaryOp::Eq => compare(left, right) == Ordering::Equal,
+        BinaryOp::Ne => compare(left, right) != Ordering::Equal,
+        BinaryOp::Lt => compare(left, right) == Ordering::Less,
+        BinaryOp::Le => compare(left, right) != Ordering::Greater,
+        BinaryOp::Gt => compare(left, right) == Ordering::Greater,
+        BinaryOp::Ge => compare(left, right) != Ordering::Less,
+        BinaryOp::StartsWith => match (left, right) {
+            (Value::String(left), Value::String(right)) => left.starts_with(right.as_str()),
+            _ => false,
+        },
+        BinaryOp::EndsWith => match (left, right) {
+            (Value::String(left), Value::String(right)) => left.ends_with(right.as_str()),
+            _ => false,
+        },
+        BinaryOp::And => truthy(left) && truthy(right),
+        BinaryOp::Or => truthy(left) || truthy(right),
+        BinaryOp::Alternative => return Ok(if truthy(left) { left } else { right }.to_owned()),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+            return arithmetic(op, left, right)
+        }
+    }))
+}
+
+/// Whether any output of `condition` against `value` is truthy. Inside a
+/// condition, a missing key simply doesn't match.
+pub fn holds(condition: &Expr, value: &Value, options: &Options, scope: &Scope) -> Result<bool> {
+    Ok(filter(value, condition, &lenient(options), scope)?
+        .iter()
+        .any(truthy))
+}
+
+fn lenient(options: &Options) -> Options {
+    Options {
+        lenient: true,
+        ..options.clone()
+    }
+}
+
+/// `null` and `false` are falsy, everything else is truthy.
+pub fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+/// Applies `tokens` to `data`, passing each result to `out`; `path` is the
+/// part of the path already walked, used to point at the failing location in
+/// errors. An optional segment (or, with `lenient`, a missing key) that finds
+/// nothing yields `null`, or nothing at all once `nested` inside a wildcard.
+fn walk(
+    data: &Value,
+    tokens: &[Token],
+    path: String,
+    nested: bool,
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let (token, rest) = match tokens.split_first() {
+        None => return out(data.to_owned()),
+        Some(split) => split,
+    };
+    let (token, optional) = match token {
+        Token::Optional(token) => (&**token, true),
+        token => (token, false),
+    };
+    let miss = |error: YajqError| -> Result<Option<Value>> {
+        let missing = matches!(
+            error,
+            YajqError::MissingKey(_) | YajqError::IndexOutOfBounds { .. }
+        ) || data.is_null();
+        if !(optional || (options.lenient && missing)) {
+            Err(error)
+        } else if nested {
+            Ok(None)
+        } else {
+            Ok(Some(Value::Null))
+        }
+    };
+    // In collect mode, wildcards other than `[]` gather everything the rest
+    // of the path yields for their elements into a single array.
+    let collecting = options.collect && !matches!(token, Token::Iterate);
+    let mut found = Vec::new();
+    let mut collect = |value| {
+        found.push(value);
+        Ok(())
+    };
+    let sink: &mut dyn FnMut(Value) -> Result<()> =
+        if collecting { &mut collect } else { &mut *out };
+    match token {
+        Token::Iterate | Token::Any | Token::Entries | Token::Filter(_) => {
+            let wildcard = match token {
+                Token::Iterate => "[]",
+                Token::Any => "*",
+                Token::Entries => "*~",
+                _ => "[?]",
+            };
+            let children = match children(data, wildcard) {
+                Ok(children) => children,
+                Err(error) => return miss(error)?.map_or(Ok(()), out),
+            };
+            for (i, (child_token, child)) in children.into_iter().enumerate() {
+                let path = format!("{}{}", path, child_token);
+                match token {
+                    Token::Entries => {
+                        let key = match &child_token {
+                            Token::Key(key) => Value::from(key.as_str()),
+                            _ => Value::from(i),
+                        };
+                        let entry = json!({"key": key, "value": child});
+                        walk(&entry, rest, path, true, options, scope, sink)?;
+                    }
+                    Token::Filter(condition) => {
+                        if holds(condition, child, options, scope)? {
+                            walk(child, rest, path, true, options, scope, sink)?;
+                        }
+                    }
+                    _ => walk(child, rest, path, true, options, scope, sink)?,
+                }
+            }
+        }
+        Token::Recurse(token) => {
+            let mut matches = Vec::new();
+            descend(data, token, path, 1, options, &mut matches);
+            for (path, value) in matches {
+                walk(&value, rest, path, true, options, scope, sink)?;
+            }
+        }
+        token => {
+            return match lookup(data, token, &path) {
+                Ok(value) => {
+                    let path = format!("{}{}", path, token);
+                    walk(&value, rest, path, nested, options, scope, out)
+                }
+                Err(error) => miss(error)?.map_or(Ok(()), out),
+            }
+        }
+    }
+    if collecting {
+        out(Value::Array(found))
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects, depth first and in document order, every value `token` selects
+/// from `data` or any value below it, searching at most `options.max_depth`
+/// levels down. `depth` is how far below the search root `data`'s children are.
+fn descend<'a>(
+    data: &'a Value,
+    token: &Token,
+    path: String,
+    depth: usize,
+    options: &Options,
+    found: &mut Vec<(String, Cow<'a, Value>)>,
+) {
+    if options.max_depth.is_some_and(|max_depth| depth > max_depth) {
+        return;
+    }
+    let children = match children(data, "..") {
+        Ok(children) => children,
+        Err(_) => return,
+    };
+    if !matches!(token, Token::Any) {
+        if let Ok(value) = lookup(data, token, &path) {
+            found.push((format!("{}{}", path, token), value));
+        }
+    }
+    for (child_token, child) in children {
+        let child_path = format!("{}{}", path, child_token);
+        if let Token::Any = token {
+            found.push((child_path.clone(), Cow::Borrowed(child)));
+        }
+        descend(child, token, child_path, depth + 1, options, found);
+    }
+}
+
+fn lookup<'a>(data: &'a Value, token: &Token, path: &str) -> Result<Cow<'a, Value>> {
+    match (token, data) {
+        (Token::Key(key), Value::Object(object)) => object
+            .get(key)
+            .map(Cow::Borrowed)
+            .ok_or_else(|| YajqError::MissingKey(key.clone())),
+        (Token::Key(key), Value::Array(array)) => {
+            let position = key.parse::<i64>().map_err(|_| {
+                YajqError::Filtering(format!(
+                    "Can't index array with key {} at {}",
+                    key,
+                    if path.is_empty() { "." } else { path }
+                ))
+            })?;
+            index(array, position, path).map(Cow::Borrowed)
+        }
+        (Token::Key(key), _) => Err(YajqError::Filtering(format!(
+            "Unit can't be filtered for key {}",
+            key
+        ))),
+        (Token::Index(i), Value::Array(array)) => index(array, *i, path).map(Cow::Borrowed),
+        (Token::Index(i), Value::String(string)) => {
+            let chars: Vec<char> = string.chars().collect();
+            index(&chars, *i, path).map(|c| Cow::Owned(Value::String(c.to_string())))
+        }
+        (Token::Index(i), Value::Object(_)) => Err(YajqError::Filtering(format!(
+            "Can't index object with number {}",
+            i
+        ))),
+        (Token::Index(i), _) => Err(YajqError::Filtering(format!(
+            "Unit can't be filtered for index {}",
+            i
+        ))),
+        (Token::Slice(start, stop, step), Value::Array(array)) => {
+            let indices = slice_indices(array.len(), *start, *stop, *step)?;
+            Ok(Cow::Owned(Value::Array(
+                indices.into_iter().map(|i| array[i].clone()).collect(),
+            )))
+        }
+        (Token::Slice(start, stop, step), Value::String(string)) => {
+            let chars: Vec<char> = string.chars().collect();
+            let indices = slice_indices(chars.len(), *start, *stop, *step)?;
+            Ok(Cow::Owned(Value::String(
+                indices.into_iter().map(|i| chars[i]).collect(),
+            )))
+        }
+        (Token::Slice(..), _) => Err(YajqError::Filtering(
+            "Can only slice arrays and strings".to_string(),
+        )),
+        (Token::Any, _)
+        | (Token::Iterate, _)
+        | (Token::Entries, _)
+        | (Token::Recurse(_), _)
+        | (Token::Filter(_), _)
+        | (Token::Optional(_), _) => unreachable!("handled by walk"),
+        (Token::Dynamic(_), _) => unreachable!("resolved by eval"),
+    }
+}
+
+/// The elements of an array or the values of an object in key order, each
+/// paired with the token that addresses it.
+fn children<'a>(data: &'a Value, wildcard: &str) -> Result<Vec<(Token, &'a Value)>> {
+    match data {
+        Value::Array(array) => Ok(array
+            .iter()
+            .enumerate()
+            .map(|(i, element)| (Token::Index(i as i64), element))
+            .collect()),
+        Value::Object(object) => Ok(object
+            .iter()
+            .map(|(key, value)| (Token::Key(key.clone()), value))
+            .collect()),
+        _ => Err(YajqError::Filtering(format!(
+            "Can't use {} on non array or object",
+            wildcard
+        ))),
+    }
+}
+
+/// Resolves a possibly negative `index` into `items`.
+fn index<'a, T>(items: &'a [T], index: i64, path: &str) -> Result<&'a T> {
+    let resolved = if index < 0 {
+        index + items.len() as i64
+    } else {
+        index
+    };
+    usize::try_from(resolved)
+        .ok()
+        .and_then(|i| items.get(i))
+        .ok_or_else(|| YajqError::IndexOutOfBounds {
+            path: if path.is_empty() {
+                ".".to_string()
+            } else {
+                path.to_string()
+            },
+            index,
+            length: items.len(),
+        })
+}
+
+/// Lists the positions selected by a `[start:stop:step]` slice over `length`
+/// items, following Python's rules for defaults, negative and
+/// out-of-range bounds.
+fn slice_indices(
+    length: usize,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: Option<i64>,
+) -> Result<Vec<usize>> {
+    let length = length as i64;
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        return Err(YajqError::Filtering(
+            "Slice step cannot be zero".to_string(),
+        ));
+    }
+    let (lower, upper) = if step < 0 {
+        (-1, length - 1)
+    } else {
+        (0, length)
+    };
+    let clamp = |bound: i64| {
+        if bound < 0 {
+            (bound + length).max(lower)
+        } else {
+            bound.min(upper)
+        }
+    };
+    let mut i = start.map_or(if step < 0 { upper } else { lower }, clamp);
+    let stop = stop.map_or(if step < 0 { lower } else { upper }, clamp);
+    let mut indices = Vec::new();
+    while (step > 0 && i < stop) || (step < 0 && i > stop) {
+        indices.push(i as usize);
+        match i.checked_add(step) {
+            Some(next) => i = next,
+            None => break,
+        }
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_expression;
+
+    fn filter_with_(data: &str, expression: &str, options: &Options) -> Result<Vec<Value>> {
+        filter(
+            &serde_json::from_str(data).unwrap(),
+            &parse_expression(expression).unwrap(),
+            options,
+            &Scope::default(),
+        )
+    }
+    fn filter_all_(data: &str, expression: &str) -> Vec<Value> {
+        filter_with_(data, expression, &Options::default()).unwrap()
+    }
+    fn single(mut outputs: Vec<Value>, expression: &str) -> Value {
+        assert_eq!(outputs.len(), 1, "{} should output one value", expression);
+        outputs.remove(0)
+    }
+    fn filter_(data: &str, expression: &str) -> Value {
+        single(filter_all_(data, expression), expression)
+    }
+    fn filter_collect_(data: &str, expression: &str) -> Value {
+        let options = Options {
+            collect: true,
+            ..Options::default()
+        };
+        single(
+            filter_with_(data, expression, &options).unwrap(),
+            expression,
+        )
+    }
+    fn parse_data_(data: &str) -> Value {
+        serde_json::from_str(data).unwrap()
+    }
+    #[test]
+    fn test_filter_simple() {
+        assert_eq!(filter_(r#"{"x": "value"}"#, "x"), parse_data_(r#""value""#))
+    }
+    #[test]
+    fn test_filter_multiple_keys() {
+        assert_eq!(
+            filter_(r#"{"x": {"y": "value"}}"#, "x.y"),
+            parse_data_(r#""value""#)
+        )
+    }
+    #[test]
+    fn test_filter_index() {
+        assert_eq!(
+            filter_(r#"{"x": ["value"]}"#, "x.0"),
+            parse_data_(r#""value""#)
+        )
+    }
+    #[test]
+    fn test_filter_star() {
+        assert_eq!(
+            filter_collect_(
+                r#"{"x": [{"name": "value1"}, {"name": "value2"}]}"#,
+                "x.*.name"
+            ),
+            parse_data_(r#"["value1", "value2"]"#)
+        )
+    }
+    #[test]
+    fn test_filter_multiple_stars() {
+        assert_eq!(
+            filter_collect_(
+                r#"{"x": [[{"name": "value1"}], [{"name": "value2"}]]}"#,
+                "x.*.*.name"
+            ),
+            parse_data_(r#"[["value1"], ["value2"]]"#)
+        )
+    }
+    #[test]
+    fn test_filter_quoted_keys() {
+        let data = r#"{"k8s.io/name": "web", "Content-Type": "json", "0": "zero", "": "empty"}"#;
+        assert_eq!(
+            filter_(data, r#".["k8s.io/name"]"#),
+            parse_data_(r#""web""#)
+        );
+        assert_eq!(
+            filter_(data, r#"."Content-Type""#),
+            parse_data_(r#""json""#)
+        );
+        assert_eq!(filter_(data, r#".["0"]"#), parse_data_(r#""zero""#));
+        assert_eq!(filter_(data, r#".[""]"#), parse_data_(r#""empty""#));
+    }
+    #[test]
+    fn test_filter_bracket_index() {
+        let data = r#"{"x": [{"y": "a"}, {"y": "b"}]}"#;
+        assert_eq!(filter_(data, "x[1].y"), parse_data_(r#""b""#));
+        assert_eq!(filter_(data, ".x.[0][\"y\"]"), parse_data_(r#""a""#));
+        assert!(filter(
+            &parse_data_(r#"{"0": 1}"#),
+            &parse_expression(".[0]").unwrap(),
+            &Options::default(),
+            &Scope::default()
+        )
+        .is_err());
+    }
+    fn filter_error_(data: &str, expression: &str) -> YajqError {
+        filter_with_(data, expression, &Options::default()).unwrap_err()
+    }
+    #[test]
+    fn test_filter_index_out_of_bounds() {
+        let data = r#"{"people": [{"name": "Adam"}, {"name": "Eve"}]}"#;
+        for expression in &["people.5", "people[5]", "people.2.name"] {
+            match filter_error_(data, expression) {
+                YajqError::IndexOutOfBounds {
+                    path,
+                    index,
+                    length,
+                } => {
+                    assert_eq!(path, ".people");
+                    assert_eq!(length, 2);
+                    assert!(index == 5 || index == 2);
+                }
+                error => panic!("unexpected error {}", error),
+            }
+        }
+    }
+    #[test]
+    fn test_filter_index_out_of_bounds_path() {
+        assert_eq!(
+            filter_error_(r#"{"a b": [[1], [2, 3]]}"#, r#"."a b".*[1]"#).to_string(),
+            r#"Index Error: index 1 is out of bounds for length 1 at ["a b"][0]"#
+        );
+        assert_eq!(
+            filter_error_("[]", ".[0]").to_string(),
+            "Index Error: index 0 is out of bounds for length 0 at ."
+        );
+    }
+    #[test]
+    fn test_filter_generators() {
+        let data = r#"{"x": [[{"name": "value1"}], [{"name": "value2"}]], "y": {"b": 2, "a": 1}}"#;
+        let values = |values: &[&str]| -> Vec<Value> {
+            values.iter().map(|value| parse_data_(value)).collect()
+        };
+        assert_eq!(
+            filter_all_(data, "x.*.*.name"),
+            values(&[r#""value1""#, r#""value2""#])
+        );
+        assert_eq!(filter_all_(data, "y.*"), values(&["1", "2"]));
+        assert_eq!(filter_all_(data, "y.*~.key"), values(&[r#""a""#, r#""b""#]));
+        assert_eq!(
+            filter_all_(data, "..name"),
+            values(&[r#""value1""#, r#""value2""#])
+        );
+        assert_eq!(filter_all_(data, "y[?(@ > 1)]"), values(&["2"]));
+        assert_eq!(
+            filter_all_(data, "x.*.*.name?"),
+            filter_all_(data, "x[][].name")
+        );
+        assert_eq!(filter_all_(data, "x.*.*.missing?"), values(&[]));
+    }
+    #[test]
+    fn test_filter_star_on_object() {
+        let data = r#"{"config": {"web": {"port": 80}, "db": {"port": 5432}}}"#;
+        assert_eq!(
+            filter_collect_(data, "config.*.port"),
+            parse_data_("[5432, 80]")
+        );
+        assert_eq!(
+            filter_collect_(r#"{"b": [1], "a": [2, 3]}"#, "*.*"),
+            parse_data_("[[2, 3], [1]]")
+        );
+    }
+    #[test]
+    fn test_filter_entries() {
+        let data = r#"{"config": {"web": {"port": 80}, "db": {"port": 5432}}}"#;
+        assert_eq!(
+            filter_collect_(data, "config.*~"),
+            parse_data_(
+                r#"[{"key": "db", "value": {"port": 5432}}, {"key": "web", "value": {"port": 80}}]"#
+            )
+        );
+        assert_eq!(
+            filter_collect_(data, "config.*~.key"),
+            parse_data_(r#"["db", "web"]"#)
+        );
+        assert_eq!(
+            filter_collect_(r#"["a", "b"]"#, ".*~"),
+            parse_data_(r#"[{"key": 0, "value": "a"}, {"key": 1, "value": "b"}]"#)
+        );
+        assert_eq!(
+            filter_error_(r#"{"a": 1}"#, "a.*~").to_string(),
+            "Filtering Error: Can't use *~ on non array or object"
+        );
+    }
+    #[test]
+    fn test_filter_recursive_descent() {
+        let data = r#"{"id": 1, "items": [{"id": 2, "sub": {"id": 3}}, {"name": "x"}], "meta": {"id": 4}}"#;
+        assert_eq!(filter_collect_(data, "..id"), parse_data_("[1, 2, 3, 4]"));
+        assert_eq!(filter_collect_(data, "items..id"), parse_data_("[2, 3]"));
+        assert_eq!(
+            filter_collect_(data, r#"..["name"]"#),
+            parse_data_(r#"["x"]"#)
+        );
+        assert_eq!(filter_collect_(data, "..nothing"), parse_data_("[]"));
+        assert_eq!(
+            filter_collect_(r#"{"a": {"b": [1]}, "c": 2}"#, "..*"),
+            parse_data_(r#"[{"b": [1]}, [1], 1, 2]"#)
+        );
+        assert_eq!(
+            filter_collect_(r#"{"a": [{"id": 1}, {"id": 2}]}"#, "a..id"),
+            parse_data_("[1, 2]")
+        );
+    }
+    #[test]
+    fn test_filter_recursive_descent_max_depth() {
+        let data = parse_data_(r#"{"id": 1, "a": {"id": 2, "b": {"id": 3}}}"#);
+        let expr = parse_expression("..id").unwrap();
+        let filter_depth = |max_depth| {
+            filter(
+                &data,
+                &expr,
+                &Options {
+                    max_depth,
+                    ..Options::default()
+                },
+                &Scope::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(filter_depth(None), vec![1, 2, 3]);
+        assert_eq!(filter_depth(Some(2)), vec![1, 2]);
+        assert_eq!(filter_depth(Some(1)), vec![1]);
+        assert!(filter_depth(Some(0)).is_empty());
+    }
+    #[test]
+    fn test_filter_comparisons() {
+        let data = r#"{"n": 2, "s": "abc"}"#;
+        assert_eq!(
+            filter_(data, "n < 3 && n <= 2 && n > 1 && n >= 2"),
+            parse_data_("true")
+        );
+        assert_eq!(filter_(data, "n == 2.0"), parse_data_("true"));
+        assert_eq!(
+            filter_(data, r#"s > "abb" && s < "b""#),
+            parse_data_("true")
+        );
+        assert_eq!(
+            filter_(data, "null < false && true < 0 && 0 < \"\""),
+            parse_data_("true")
+        );
+        assert_eq!(filter_(data, r#"s starts_with "ab""#), parse_data_("true"));
+        assert_eq!(filter_(data, r#"s ends_with "ab""#), parse_data_("false"));
+        assert_eq!(filter_(data, r#"n ends_with "2""#), parse_data_("false"));
+    }
+    #[test]
+    fn test_filter_predicates() {
+        let data = r#"{"people": [
+            {"name": "Adam", "email": "adams@company.com", "age": 40},
+            {"name": "Eve", "email": "eve@home.org", "age": 30, "phone": "555"},
+            {"name": "Bob", "email": "bob@company.com", "age": 25}
+        ]}"#;
+        assert_eq!(
+            filter_collect_(data, r#"people[?(@.email ends_with "company.com")].name"#),
+            parse_data_(r#"["Adam", "Bob"]"#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people[?(@.age >= 30 && !(@.name == \"Eve\"))].name"),
+            parse_data_(r#"["Adam"]"#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people[?(@.age < 26 || @.phone)].name"),
+            parse_data_(r#"["Eve", "Bob"]"#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people[?(!@.phone)].name"),
+            parse_data_(r#"["Adam", "Bob"]"#)
+        );
+        let flags = r#"[{"n": 1, "active": true}, {"n": 2, "active": false},
+            {"n": 3, "active": null}, {"n": 4}]"#;
+        assert_eq!(
+            filter_collect_(flags, ".[?(@.active)].n"),
+            parse_data_("[1]")
+        );
+        assert_eq!(
+            filter_collect_(flags, r#".[?(has("active"))].n"#),
+            parse_data_("[1, 2, 3]")
+        );
+        assert_eq!(
+            filter_collect_(data, "people[?@.age > 100]"),
+            parse_data_("[]")
+        );
+        assert_eq!(
+            filter_collect_(r#"{"a": {"x": 1}, "b": {"x": 2}}"#, ".[?(@.x > 1)]"),
+            parse_data_(r#"[{"x": 2}]"#)
+        );
+        assert_eq!(
+            filter_collect_(r#"[1, 5, 3]"#, ".[?(@ > 2)]"),
+            parse_data_("[5, 3]")
+        );
+        assert_eq!(
+            filter_error_(r#"{"a": 1}"#, "a[?(@)]").to_string(),
+            "Filtering Error: Can't use [?] on non array or object"
+        );
+    }
+    fn filter_lenient_(data: &str, expression: &str) -> Result<Vec<Value>> {
+        let options = Options {
+            lenient: true,
+            ..Options::default()
+        };
+        filter_with_(data, expression, &options)
+    }
+    #[test]
+    fn test_filter_optional_segments() {
+        let data = r#"{"people": [{"name": "Adam"}, {"name": "Eve", "phone": "555"}], "n": 1}"#;
+        assert_eq!(filter_collect_(data, "missing?.name"), parse_data_("null"));
+        assert_eq!(
+            filter_collect_(data, "people.0.phone?"),
+            parse_data_("null")
+        );
+        assert_eq!(
+            filter_collect_(data, "people[5]?.name"),
+            parse_data_("null")
+        );
+        assert_eq!(
+            filter_collect_(data, r#"people.1["phone"]?"#),
+            parse_data_(r#""555""#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people.*.phone?"),
+            parse_data_(r#"["555"]"#)
+        );
+        assert_eq!(filter_collect_(data, "n.*?"), parse_data_("null"));
+        assert_eq!(filter_collect_(data, "n.x?"), parse_data_("null"));
+        assert!(matches!(
+            filter_error_(data, "people.0?.phone"),
+            YajqError::MissingKey(_)
+        ));
+    }
+    #[test]
+    fn test_filter_lenient() {
+        let data = r#"{"people": [{"name": "Adam"}, {"name": "Eve", "phone": "555"}], "n": 1}"#;
+        assert_eq!(
+            filter_lenient_(data, "missing.name").unwrap(),
+            vec![parse_data_("null")]
+        );
+        assert_eq!(
+            filter_lenient_(data, "people.5").unwrap(),
+            vec![parse_data_("null")]
+        );
+        assert_eq!(
+            filter_lenient_(data, "people.*.phone").unwrap(),
+            vec![parse_data_(r#""555""#)]
+        );
+        assert_eq!(
+            filter_lenient_(data, "people[?(@.name)].name").unwrap(),
+            vec![parse_data_(r#""Adam""#), parse_data_(r#""Eve""#)]
+        );
+        assert!(filter_lenient_(data, "n.x").is_err());
+    }
+    #[test]
+    fn test_filter_pipes() {
+        let data =
+            r#"{"people": [{"name": "Adam", "email": "a@x"}, {"name": "Eve", "email": "e@x"}]}"#;
+        assert_eq!(
+            filter_collect_(data, "people | .[0] | .email"),
+            parse_data_(r#""a@x""#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people | .*.name"),
+            parse_data_(r#"["Adam", "Eve"]"#)
+        );
+        assert_eq!(
+            filter_collect_(data, "people.-1 | name == \"Eve\""),
+            parse_data_("true")
+        );
+    }
+    #[test]
+    fn test_filter_streams() {
+        let data = r#"{"people": [{"name": "Adam", "email": "a@x"}, {"name": "Eve"}]}"#;
+        assert_eq!(
+            filter_all_(data, "people[] | .name"),
+            vec![parse_data_(r#""Adam""#), parse_data_(r#""Eve""#)]
+        );
+        assert_eq!(
+            filter_all_(data, "people.0.name, people.1.name"),
+            vec![parse_data_(r#""Adam""#), parse_data_(r#""Eve""#)]
+        );
+        assert_eq!(
+            filter_all_(data, "people[].email?"),
+            vec![parse_data_(r#""a@x""#)]
+        );
+        assert_eq!(
+            filter_all_(data, "(people.0, people.1) | .name == \"Eve\""),
+            vec![parse_data_("false"), parse_data_("true")]
+        );
+        assert_eq!(
+            filter_all_(data, "people[].name == (\"Adam\", \"Eve\")"),
+            vec![
+                parse_data_("true"),
+                parse_data_("false"),
+                parse_data_("false"),
+                parse_data_("true")
+            ]
+        );
+        assert_eq!(
+            filter_collect_(data, "people.*.[]"),
+            parse_data_(r#"["a@x", "Adam", "Eve"]"#)
+        );
+        assert!(filter_all_(r#"{"a": []}"#, "a[]").is_empty());
+    }
+    #[test]
+    fn test_filter_negative_index() {
+        let data = r#"{"people": [{"name": "Adam"}, {"name": "Eve"}]}"#;
+        assert_eq!(filter_(data, "people.-1.name"), parse_data_(r#""Eve""#));
+        assert_eq!(filter_(data, "people[-2].name"), parse_data_(r#""Adam""#));
+        assert_eq!(filter_(data, "people.0.name[-1]"), parse_data_(r#""m""#));
+        assert_eq!(
+            filter_error_(data, "people[-3]").to_string(),
+            "Index Error: index -3 is out of bounds for length 2 at .people"
+        );
+    }
+    #[test]
+    fn test_filter_slices() {
+        let data = r#"{"xs": [0, 1, 2, 3, 4], "name": "Adam Smith", "word": "héllo"}"#;
+        assert_eq!(filter_(data, "xs[1:3]"), parse_data_("[1, 2]"));
+        assert_eq!(filter_(data, "xs[::2]"), parse_data_("[0, 2, 4]"));
+        assert_eq!(filter_(data, "xs[-2:]"), parse_data_("[3, 4]"));
+        assert_eq!(filter_(data, "xs[:-3]"), parse_data_("[0, 1]"));
+        assert_eq!(filter_(data, "xs[::-1]"), parse_data_("[4, 3, 2, 1, 0]"));
+        assert_eq!(filter_(data, "xs[3:0:-2]"), parse_data_("[3, 1]"));
+        assert_eq!(filter_(data, "xs[10:20]"), parse_data_("[]"));
+        assert_eq!(filter_(data, "xs[-10:2]"), parse_data_("[0, 1]"));
+        assert_eq!(filter_(data, "name[0:4]"), parse_data_(r#""Adam""#));
+        assert_eq!(filter_(data, "word[1:3]"), parse_data_(r#""él""#));
+        assert_eq!(
+            filter_error_(data, "xs[::0]").to_string(),
+            "Filtering Error: Slice step cannot be zero"
+        );
+        assert_eq!(
+            filter_error_(r#"{"a": 1}"#, "a[1:]").to_string(),
+            "Filtering Error: Can only slice arrays and strings"
+        );
+    }
+    #[test]
+    fn test_filter_slice_extreme_steps() {
+        let data = r#"{"xs": [0, 1, 2], "name": "abc"}"#;
+        assert_eq!(
+            filter_(data, "xs[1::9223372036854775807]"),
+            parse_data_("[1]")
+        );
+        assert_eq!(
+            filter_(data, "xs[1::-9223372036854775807]"),
+            parse_data_("[1]")
+        );
+        assert_eq!(
+            filter_(data, "name[1::9223372036854775807]"),
+            parse_data_(r#""b""#)
+        );
+        assert_eq!(
+            filter_(data, "name[::-9223372036854775807]"),
+            parse_data_(r#""c""#)
+        );
+    }
+    #[test]
+    fn test_filter_errors() {
+        let data = r#"{"x": [1], "y": "s", "z": {"0": 1}, "n": 1}"#;
+        assert_eq!(
+            filter_error_(data, "w").to_string(),
+            "Filtering Error: Key w not in dict"
+        );
+        assert_eq!(
+            filter_error_(data, "y.w").to_string(),
+            "Filtering Error: Unit can't be filtered for key w"
+        );
+        assert_eq!(
+            filter_error_(data, "n[0]").to_string(),
+            "Filtering Error: Unit can't be filtered for index 0"
+        );
+        assert_eq!(
+            filter_error_(data, "z[0]").to_string(),
+            "Filtering Error: Can't index object with number 0"
+        );
+        assert_eq!(
+            filter_error_(data, "n.*").to_string(),
+            "Filtering Error: Can't use * on non array or object"
+        );
+        assert_eq!(
+            filter_error_(data, "x.first").to_string(),
+            "Filtering Error: Can't index array with key first at .x"
+        );
+        assert_eq!(
+            filter_error_(r#"[1]"#, "first").to_string(),
+            "Filtering Error: Can't index array with key first at ."
+        );
+    }
+    #[test]
+    fn test_filter_identity_and_literals() {
+        assert_eq!(filter_(r#"{"x": 1}"#, "."), parse_data_(r#"{"x": 1}"#));
+        assert_eq!(filter_(r#"{"x": 1}"#, r#""x""#), parse_data_(r#""x""#));
+        assert_eq!(filter_(r#"{"x": 1}"#, "(.).x"), parse_data_("1"));
+    }
+    #[test]
+    fn test_filter_operators() {
+        let data = r#"{"x": 1, "y": "a", "z": null}"#;
+        assert_eq!(filter_(data, "x == 1"), parse_data_("true"));
+        assert_eq!(filter_(data, r#"y != "a""#), parse_data_("false"));
+        assert_eq!(filter_(data, "z || x"), parse_data_("true"));
+        assert_eq!(filter_(data, "z && x"), parse_data_("false"));
+        assert_eq!(filter_(data, "!z"), parse_data_("true"));
+    }
+    #[test]
+    fn test_filter_arithmetic() {
+        let data = r#"{"items": [{"price": 3, "qty": 2}, {"price": 1.5, "qty": 4}], "name": "a"}"#;
+        assert_eq!(
+            filter_all_(data, "items[] | .price * .qty"),
+            vec![parse_data_("6"), parse_data_("6.0")]
+        );
+        assert_eq!(filter_(data, "1 + 2 * 3 - 4 / 2 % 3"), parse_data_("5"));
+        assert_eq!(filter_(data, "-(1 + 2) * -items.0.qty"), parse_data_("6"));
+        assert_eq!(
+            filter_(data, r#"name + "-" + name"#),
+            parse_data_(r#""a-a""#)
+        );
+        assert_eq!(filter_(data, "items.0.qty * 2 > 3"), parse_data_("true"));
+        assert_eq!(
+            filter_error_(data, "name - 1").to_string(),
+            r#"Type Error: Cannot apply - to string ("a") and number (1)"#
+        );
+    }
+    #[test]
+    fn test_filter_construction() {
+        let data = r#"{"people": [{"name": "A", "email": "a@x", "id": "p1"}, {"name": "B", "email": "b@x", "id": "p2"}]}"#;
+        assert_eq!(
+            filter_(data, "[people[].email]"),
+            parse_data_(r#"["a@x", "b@x"]"#)
+        );
+        assert_eq!(
+            filter_all_(data, "people[] | {name: .name, contact: .email}"),
+            vec![
+                parse_data_(r#"{"name": "A", "contact": "a@x"}"#),
+                parse_data_(r#"{"name": "B", "contact": "b@x"}"#)
+            ]
+        );
+        assert_eq!(
+            filter_(data, "people.0 | {(.id): .name, email}"),
+            parse_data_(r#"{"p1": "A", "email": "a@x"}"#)
+        );
+        assert_eq!(
+            filter_all_(data, r#"{name: people[].name, n: (1, 2)}"#),
+            vec![
+                parse_data_(r#"{"name": "A", "n": 1}"#),
+                parse_data_(r#"{"name": "A", "n": 2}"#),
+                parse_data_(r#"{"name": "B", "n": 1}"#),
+                parse_data_(r#"{"name": "B", "n": 2}"#)
+            ]
+        );
+        assert_eq!(filter_(data, "[people[] | empty]"), parse_data_("[]"));
+        assert_eq!(
+            filter_error_(data, "{(1): 2}").to_string(),
+            "Type Error: Object keys must be strings, not number (1)"
+        );
+    }
+    #[test]
+    fn test_filter_interpolation() {
+        let data = r#"{"people": [{"name": "Adam Smith", "email": "adams@company.com", "age": 40}, {"name": "Eve", "email": null, "age": 2.5}]}"#;
+        assert_eq!(
+            filter_all_(data, r#"people[] | "\(.name) <\(.email)>""#),
+            vec![
+                parse_data_(r#""Adam Smith <adams@company.com>""#),
+                parse_data_(r#""Eve <null>""#)
+            ]
+        );
+        assert_eq!(
+            filter_(
+                data,
+                r#""\(people[0] | {age}), \(people[0].age | . * 2 | [.])""#
+            ),
+            parse_data_(r#""{\"age\":40}, [80]""#)
+        );
+        assert_eq!(
+            filter_all_(data, r#""\(people[].age)-\("a", "b")""#),
+            vec![
+                parse_data_(r#""40-a""#),
+                parse_data_(r#""40-b""#),
+                parse_data_(r#""2.5-a""#),
+                parse_data_(r#""2.5-b""#)
+            ]
+        );
+        assert_eq!(
+            filter_(data, r#""outer \("inner \(people | length)")""#),
+            parse_data_(r#""outer inner 2""#)
+        );
+        assert_eq!(
+            filter_(data, r#"{"\(people[0].email)": people[0].name}"#),
+            parse_data_(r#"{"adams@company.com": "Adam Smith"}"#)
+        );
+    }
+
+    #[test]
+    fn test_filter_alternative() {
+        let data = r#"{"a": null, "b": false, "c": 1, "d": [null, 2, false, 3]}"#;
+        assert_eq!(filter_(data, "a // b // c"), parse_data_("1"));
+        assert_eq!(filter_(data, "missing // c"), parse_data_("1"));
+        assert_eq!(filter_(data, "missing.deeper // c"), parse_data_("1"));
+        assert_eq!(filter_(data, "c // missing"), parse_data_("1"));
+        assert_eq!(
+            filter_all_(data, "d[] // 0"),
+            vec![parse_data_("2"), parse_data_("3")]
+        );
+        assert_eq!(filter_(data, "[d[] | . // 0]"), parse_data_("[0, 2, 0, 3]"));
+        assert_eq!(filter_(data, "(a // b) == false"), parse_data_("true"));
+        assert!(filter_with_(data, "a // missing", &Options::default()).is_err());
+    }
+    #[test]
+    fn test_filter_conditionals() {
+        let data = r#"{"people": [{"age": 10}, {"age": 30}, {"age": 70}, {}]}"#;
+        assert_eq!(
+            filter_(
+                data,
+                r#"[people[] | if .age >= 65 then "senior" elif .age >= 18 then "adult" elif .age then "minor" else "unknown" end]"#
+            ),
+            parse_data_(r#"["minor", "adult", "senior", "unknown"]"#)
+        );
+        assert_eq!(
+            filter_(data, "[people[] | if .age > 20 then .age end]"),
+            parse_data_(r#"[{"age": 10}, 30, 70, {}]"#)
+        );
+        assert_eq!(
+            filter_all_(data, "if true then 1, 2 else 3 end"),
+            vec![parse_data_("1"), parse_data_("2")]
+        );
+    }
+    #[test]
+    fn test_filter_variables() {
+        let data = r#"{"threshold": 2, "items": [{"n": 1}, {"n": 3}], "x": 5}"#;
+        assert_eq!(
+            filter_(data, "threshold as $t | [items[] | select(.n > $t) | .n]"),
+            parse_data_("[3]")
+        );
+        assert_eq!(
+            filter_all_(data, "items[] as $item | $item.n + x"),
+            vec![parse_data_("6"), parse_data_("8")]
+        );
+        assert_eq!(
+            filter_(data, "1 as $x | 2 as $y | [$x, $y, (3 as $x | $x), $x]"),
+            parse_data_("[1, 2, 3, 1]")
+        );
+        assert_eq!(
+            filter_(data, "x as $x | [items[?(@.n < $x)]] | length"),
+            parse_data_("2")
+        );
+        assert_eq!(
+            filter_error_(data, "$missing").to_string(),
+            "Filtering Error: Variable $missing is not defined"
+        );
+    }
+    #[test]
+    fn test_filter_dynamic_keys() {
+        let data = r#"{"services": {"api": {"port": 80}, "db": {"port": 5432}}, "name": "db", "list": [10, 20, 30]}"#;
+        assert_eq!(filter_(data, "services[name].port"), parse_data_("5432"));
+        assert_eq!(
+            filter_(data, r#""api" as $s | services[$s].port"#),
+            parse_data_("80")
+        );
+        assert_eq!(filter_(data, "list[1 + 1]"), parse_data_("30"));
+        assert_eq!(filter_(data, r#"list[("" + "0")]"#), parse_data_("10"));
+        assert_eq!(
+            filter_all_(data, r#"services["api", "db"].port"#),
+            vec![parse_data_("80"), parse_data_("5432")]
+        );
+        assert_eq!(
+            filter_(data, r#"services[("x" + "y")]?"#),
+            parse_data_("null")
+        );
+        assert_eq!(
+            filter_error_(data, "list[list]").to_string(),
+            "Type Error: Cannot index with array ([10,20,30])"
+        );
+    }
+    #[test]
+    fn test_filter_reduce() {
+        let data = r#"{"items": [{"k": "a", "n": 1}, {"k": "b", "n": 2}, {"k": "a", "n": 3}]}"#;
+        assert_eq!(
+            filter_(data, "reduce items[] as $item (0; . + $item.n)"),
+            parse_data_("6")
+        );
+        assert_eq!(
+            filter_(
+                data,
+                "reduce items[] as $item ({}; . + {($item.k): ((.[$item.k] // 0) + $item.n)})"
+            ),
+            parse_data_(r#"{"a": 4, "b": 2}"#)
+        );
+        assert_eq!(
+            filter_all_(data, "reduce items[] as $item (0, 10; . + $item.n)"),
+            vec![parse_data_("6"), parse_data_("16")]
+        );
+        assert_eq!(
+            filter_(data, "reduce empty as $item (7; . + 1)"),
+            parse_data_("7")
+        );
+        assert_eq!(
+            filter_(data, "reduce items[] as $item (0; empty)"),
+            parse_data_("null")
+        );
+    }
+    #[test]
+    fn test_filter_foreach() {
+        let data = r#"{"items": [1, 2, 3]}"#;
+        assert_eq!(
+            filter_all_(data, "foreach items[] as $x (0; . + $x)"),
+            vec![parse_data_("1"), parse_data_("3"), parse_data_("6")]
+        );
+        assert_eq!(
+            filter_(data, "[foreach items[] as $x (0; . + $x; [$x, .])]"),
+            parse_data_("[[1, 1], [2, 3], [3, 6]]")
+        );
+        assert_eq!(
+            filter_(data, "[foreach items[] as $x (0; . + $x; select(. > 2))]"),
+            parse_data_("[3, 6]")
+        );
+        assert_eq!(
+            filter_(data, "[foreach items[] as $x (0; (. + $x), (. - $x))]"),
+            parse_data_("[1, -1, 1, -3, 0, -6]")
+        );
+    }
+    #[test]
+    fn test_filter_definitions() {
+        let data = r#"{"items": [1, 2, 3], "person": {"address": {"city": "Paris"}}}"#;
+        assert_eq!(
+            filter_(data, "def city: person.address.city; city + \"!\""),
+            parse_data_(r#""Paris!""#)
+        );
+        assert_eq!(
+            filter_(
+                data,
+                "def fact: if . <= 1 then 1 else . * (. - 1 | fact) end; 10 | fact"
+            ),
+            parse_data_("3628800")
+        );
+        assert_eq!(
+            filter_(data, "def twice(f): f | f; items | map(twice(. * 10))"),
+            parse_data_("[100, 200, 300]")
+        );
+        assert_eq!(
+            filter_(
+                data,
+                "def upto($n): if . < $n then ., (. + 1 | upto($n)) else empty end; (items | length) as $len | [0 | upto($len)]"
+            ),
+            parse_data_("[0, 1, 2]")
+        );
+        assert_eq!(
+            filter_(data, "def f: 1; def g: f; def f: 2; [g, f]"),
+            parse_data_("[1, 2]")
+        );
+        assert_eq!(
+            filter_(data, "def f(x): x; def g: 10; def h(g): f(g); 5 | h(. + 1)"),
+            parse_data_("6")
+        );
+        assert_eq!(
+            filter_(data, "1 as $x | def f: $x; 2 as $x | [f, $x]"),
+            parse_data_("[1, 2]")
+        );
+        assert_eq!(
+            filter_(data, "def length: 42; [length, (items | length)]"),
+            parse_data_("[42, 42]")
+        );
+        assert_eq!(
+            filter_error_(data, "def f(x): x; .f").to_string(),
+            "Filtering Error: Key f not in dict"
+        );
+    }
+    #[test]
+    fn test_filter_keys_named_like_builtins() {
+        let data = r#"{"type": "svc", "count": 5, "values": [1]}"#;
+        // A bare built-in name is a call; `.name` always reads the key.
+        assert_eq!(
+            filter_all_(data, "type, count, values"),
+            vec![
+                parse_data_(r#""object""#),
+                parse_data_("3"),
+                parse_data_(r#"[5, "svc", [1]]"#)
+            ]
+        );
+        assert_eq!(
+            filter_all_(data, ".type, .count, .values"),
+            vec![
+                parse_data_(r#""svc""#),
+                parse_data_("5"),
+                parse_data_("[1]")
+            ]
+        );
+        assert_eq!(filter_(data, "values.0"), parse_data_("1"));
+        assert_eq!(filter_(data, "values[0]"), parse_data_("1"));
+    }
+    #[test]
+    fn test_filter_unknown_function() {
+        let error = filter(
+            &Value::Null,
+            &parse_expression("f(1)").unwrap(),
+            &Options::default(),
+            &Scope::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Filtering Error: Unknown function f/1");
+    }
+}
@@ -0,0 +1,585 @@
+use crate::parser::ParseError;
+use serde_json::Number;
+use std::fmt;
+use std::iter::Peekable;
+use std::mem;
+use std::str::CharIndices;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lexeme {
+    Dot,
+    DotDot,
+    Star,
+    Tilde,
+    Minus,
+    Plus,
+    Slash,
+    SlashSlash,
+    Percent,
+    Colon,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Semicolon,
+    Comma,
+    Pipe,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Question,
+    At,
+    And,
+    Or,
+    Bang,
+    Ident(String),
+    Variable(String),
+    Number(Number),
+    Str(String),
+    /// A string literal with `\(expr)` interpolations
+    Template(Vec<Fragment>),
+}
+
+/// A part of an interpolated string literal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fragment {
+    Text(String),
+    /// The tokens of an interpolated expression, ending with the `)` that
+    /// closes it
+    Code(Vec<Spanned>),
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lexeme::Dot => write!(f, "'.'"),
+            Lexeme::DotDot => write!(f, "'..'"),
+            Lexeme::Star => write!(f, "'*'"),
+            Lexeme::Tilde => write!(f, "'~'"),
+            Lexeme::Minus => write!(f, "'-'"),
+            Lexeme::Plus => write!(f, "'+'"),
+            Lexeme::Slash => write!(f, "'/'"),
+            Lexeme::SlashSlash => write!(f, "'//'"),
+            Lexeme::Percent => write!(f, "'%'"),
+            Lexeme::Colon => write!(f, "':'"),
+            Lexeme::LBracket => write!(f, "'['"),
+            Lexeme::RBracket => write!(f, "']'"),
+            Lexeme::LParen => write!(f, "'('"),
+            Lexeme::RParen => write!(f, "')'"),
+            Lexeme::LBrace => write!(f, "'{{'"),
+            Lexeme::RBrace => write!(f, "'}}'"),
+            Lexeme::Semicolon => write!(f, "';'"),
+            Lexeme::Comma => write!(f, "','"),
+            Lexeme::Pipe => write!(f, "'|'"),
+            Lexeme::Eq => write!(f, "'=='"),
+            Lexeme::Ne => write!(f, "'!='"),
+            Lexeme::Lt => write!(f, "'<'"),
+            Lexeme::Le => write!(f, "'<='"),
+            Lexeme::Gt => write!(f, "'>'"),
+            Lexeme::Ge => write!(f, "'>='"),
+            Lexeme::Question => write!(f, "'?'"),
+            Lexeme::At => write!(f, "'@'"),
+            Lexeme::And => write!(f, "'&&'"),
+            Lexeme::Or => write!(f, "'||'"),
+            Lexeme::Bang => write!(f, "'!'"),
+            Lexeme::Ident(name) => write!(f, "identifier {}", name),
+            Lexeme::Variable(name) => write!(f, "variable ${}", name),
+            Lexeme::Number(number) => write!(f, "number {}", number),
+            Lexeme::Str(string) => write!(f, "string {:?}", string),
+            Lexeme::Template(_) => write!(f, "interpolated string"),
+        }
+    }
+}
+
+/// A lexeme together with the byte offsets it starts and ends at in the
+/// source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spanned {
+    pub lexeme: Lexeme,
+    pub offset: usize,
+    pub end: usize,
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Spanned>, ParseError> {
+    Lexer {
+        source,
+        chars: source.char_indices().peekable(),
+    }
+    .run(None)
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    /// Tokenizes the source, or within a string, the expression interpolated
+    /// by the `\(` at `interpolation`, up to and including its closing `)`.
+    fn run(&mut self, interpolation: Option<usize>) -> Result<Vec<Spanned>, ParseError> {
+        let mut spanned: Vec<Spanned> = Vec::new();
+        let mut depth = 0;
+        while let Some(&(offset, c)) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.chars.next();
+                continue;
+            }
+            let lexeme = if after_dot(&spanned, offset) && is_ident_char(c) {
+                // Path segments after a dot may start with a digit, so that
+                // `people.0` and `people.-1` keep addressing array elements.
+                Lexeme::Ident(self.take_while(is_ident_char))
+            } else if c.is_ascii_digit() {
+                self.number(offset)?
+            } else if c.is_alphabetic() || c == '_' {
+                Lexeme::Ident(self.take_while(is_ident_char))
+            } else if c == '"' {
+                self.string(offset)?
+            } else if c == '$' {
+                self.chars.next();
+                match self.take_while(is_ident_char) {
+                    name if name.is_empty() => {
+                        return Err(ParseError::new(
+                            "Expected a variable name after '$'".to_string(),
+                            self.source,
+                            offset,
+                        ))
+                    }
+                    name => Lexeme::Variable(name),
+                }
+            } else {
+                self.chars.next();
+                match c {
+                    '.' if self.eat('.') => Lexeme::DotDot,
+                    '.' => Lexeme::Dot,
+                    '*' => Lexeme::Star,
+                    '~' => Lexeme::Tilde,
+                    '-' => Lexeme::Minus,
+                    '+' => Lexeme::Plus,
+                    '/' if self.eat('/') => Lexeme::SlashSlash,
+                    '/' => Lexeme::Slash,
+                    '%' => Lexeme::Percent,
+                    ':' => Lexeme::Colon,
+                    '[' => Lexeme::LBracket,
+                    ']' => Lexeme::RBracket,
+                    '(' => Lexeme::LParen,
+                    ')' => Lexeme::RParen,
+                    '{' => Lexeme::LBrace,
+                    '}' => Lexeme::RBrace,
+                    ';' => Lexeme::Semicolon,
+                    '=' if self.eat('=') => Lexeme::Eq,
+                    '!' if self.eat('=') => Lexeme::Ne,
+                    '!' => Lexeme::Bang,
+                    '<' if self.eat('=') => Lexeme::Le,
+                    '<' => Lexeme::Lt,
+                    '>' if self.eat('=') => Lexeme::Ge,
+                    '>' => Lexeme::Gt,
+                    '?' => Lexeme::Question,
+                    '@' => Lexeme::At,
+                    '&' if self.eat('&') => Lexeme::And,
+                    '|' if self.eat('|') => Lexeme::Or,
+                    '|' => Lexeme::Pipe,
+                    ',' => Lexeme::Comma,
+                    _ => {
+                        return Err(ParseError::new(
+                            format!("Unexpected character {:?}", c),
+                            self.source,
+                            offset,
+                        ))
+                    }
+                }
+            };
+            let end = self.chars.peek().map_or(self.source.len(), |&(end, _)| end);
+            let closed = interpolation.is_some() && lexeme == Lexeme::RParen && depth == 0;
+            match lexeme {
+                Lexeme::LParen => depth += 1,
+                Lexeme::RParen => depth -= 1,
+                _ => {}
+            }
+            spanned.push(Spanned {
+                lexeme,
+                offset,
+                end,
+            });
+            if closed {
+                return Ok(spanned);
+            }
+        }
+        match interpolation {
+            Some(offset) => Err(ParseError::new(
+                "Unterminated interpolation".to_string(),
+                self.source,
+                offset,
+            )),
+            None => Ok(spanned),
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.chars.next_if(|&(_, c)| c == expected).is_some()
+    }
+
+    fn take_while(&mut self, predicate: fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some((_, c)) = self.chars.next_if(|&(_, c)| predicate(c)) {
+            taken.push(c);
+        }
+        taken
+    }
+
+    fn number(&mut self, offset: usize) -> Result<Lexeme, ParseError> {
+        let mut text = self.take_while(|c| c.is_ascii_digit());
+        let mut lookahead = self.chars.clone();
+        if let (Some((_, '.')), Some((_, next))) = (lookahead.next(), lookahead.next()) {
+            if next.is_ascii_digit() {
+                self.chars.next();
+                text.push('.');
+                text.push_str(&self.take_while(|c| c.is_ascii_digit()));
+            }
+        }
+        if let Some((_, e)) = self.chars.next_if(|&(_, c)| c == 'e' || c == 'E') {
+            text.push(e);
+            if let Some((_, sign)) = self.chars.next_if(|&(_, c)| c == '+' || c == '-') {
+                text.push(sign);
+            }
+            text.push_str(&self.take_while(|c| c.is_ascii_digit()));
+        }
+        serde_json::from_str(&text)
+            .map(Lexeme::Number)
+            .map_err(|_| ParseError::new(format!("Invalid number {}", text), self.source, offset))
+    }
+
+    /// Decodes a string literal, with JSON escapes and `\(expr)`
+    /// interpolations.
+    fn string(&mut self, offset: usize) -> Result<Lexeme, ParseError> {
+        self.chars.next();
+        let mut fragments = Vec::new();
+        let mut text = String::new();
+        while let Some((position, c)) = self.chars.next() {
+            match c {
+                '"' if fragments.is_empty() => return Ok(Lexeme::Str(text)),
+                '"' => {
+                    if !text.is_empty() {
+                        fragments.push(Fragment::Text(text));
+                    }
+                    return Ok(Lexeme::Template(fragments));
+                }
+                '\\' if self.eat('(') => {
+                    if !text.is_empty() {
+                        fragments.push(Fragment::Text(mem::take(&mut text)));
+                    }
+                    fragments.push(Fragment::Code(self.run(Some(position))?));
+                }
+                '\\' => text.push(self.escape(position)?),
+                c if c.is_control() => {
+                    return Err(ParseError::new(
+                        format!("Unescaped control character {:?} in string literal", c),
+                        self.source,
+                        position,
+                    ))
+                }
+                c => text.push(c),
+            }
+        }
+        Err(ParseError::new(
+            "Unterminated string literal".to_string(),
+            self.source,
+            offset,
+        ))
+    }
+
+    /// Decodes the escape sequence starting with the `\` at `offset`.
+    fn escape(&mut self, offset: usize) -> Result<char, ParseError> {
+        let invalid = |lexer: &Self| {
+            let end = lexer
+                .chars
+                .clone()
+                .next()
+                .map_or(lexer.source.len(), |(end, _)| end);
+            ParseError::new(
+                format!("Invalid escape sequence {}", &lexer.source[offset..end]),
+                lexer.source,
+                offset,
+            )
+        };
+        let escaped = match self.chars.next() {
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, '/')) => '/',
+            Some((_, 'b')) => '\u{8}',
+            Some((_, 'f')) => '\u{c}',
+            Some((_, 'n')) => '\n',
+            Some((_, 'r')) => '\r',
+            Some((_, 't')) => '\t',
+            Some((_, 'u')) => {
+                let unit = self.hex().ok_or_else(|| invalid(self))?;
+                // Characters outside the Basic Multilingual Plane are
+                // escaped as a pair of UTF-16 surrogates.
+                let code = if (0xD800..0xDC00).contains(&unit) {
+                    let low = if self.eat('\\') && self.eat('u') {
+                        self.hex()
+                    } else {
+                        None
+                    };
+                    match low {
+                        Some(low) if (0xDC00..0xE000).contains(&low) => {
+                            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                        }
+                        _ => return Err(invalid(self)),
+                    }
+                } else {
+                    unit
+                };
+                return std::char::from_u32(code).ok_or_else(|| invalid(self));
+            }
+            _ => return Err(invalid(self)),
+        };
+        Ok(escaped)
+    }
+
+    /// Parses the four hex digits of a `\u` escape.
+    fn hex(&mut self) -> Option<u32> {
+        let digits: String = (0..4)
+            .filter_map(|_| self.chars.next_if(|(_, c)| c.is_ascii_hexdigit()))
+            .map(|(_, c)| c)
+            .collect();
+        if digits.len() == 4 {
+            u32::from_str_radix(&digits, 16).ok()
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the lexeme starting at `offset` is a path segment, i.e. directly
+/// follows `.`, `..` or `.-`; `. - 1` is a subtraction.
+fn after_dot(spanned: &[Spanned], offset: usize) -> bool {
+    let mut previous = spanned.iter().rev();
+    match previous.next() {
+        Some(last) if last.end != offset => false,
+        Some(Spanned {
+            lexeme: Lexeme::Dot,
+            ..
+        })
+        | Some(Spanned {
+            lexeme: Lexeme::DotDot,
+            ..
+        }) => true,
+        Some(
+            minus @ Spanned {
+                lexeme: Lexeme::Minus,
+                ..
+            },
+        ) => previous
+            .next()
+            .is_some_and(|dot| dot.lexeme == Lexeme::Dot && dot.end == minus.offset),
+        _ => false,
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lexemes(source: &str) -> Vec<Lexeme> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|spanned| spanned.lexeme)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_path() {
+        assert_eq!(
+            lexemes("a.12.*.c"),
+            vec![
+                Lexeme::Ident("a".to_string()),
+                Lexeme::Dot,
+                Lexeme::Ident("12".to_string()),
+                Lexeme::Dot,
+                Lexeme::Star,
+                Lexeme::Dot,
+                Lexeme::Ident("c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_negative_segment() {
+        assert_eq!(
+            lexemes("a.-1.5[-1:]"),
+            vec![
+                Lexeme::Ident("a".to_string()),
+                Lexeme::Dot,
+                Lexeme::Minus,
+                Lexeme::Ident("1".to_string()),
+                Lexeme::Dot,
+                Lexeme::Ident("5".to_string()),
+                Lexeme::LBracket,
+                Lexeme::Minus,
+                Lexeme::Number(1.into()),
+                Lexeme::Colon,
+                Lexeme::RBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_subtraction() {
+        assert_eq!(
+            lexemes("a.b-1 . - 2"),
+            vec![
+                Lexeme::Ident("a".to_string()),
+                Lexeme::Dot,
+                Lexeme::Ident("b".to_string()),
+                Lexeme::Minus,
+                Lexeme::Number(1.into()),
+                Lexeme::Dot,
+                Lexeme::Minus,
+                Lexeme::Number(2.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_recursive_descent() {
+        assert_eq!(
+            lexemes("a..0.b"),
+            vec![
+                Lexeme::Ident("a".to_string()),
+                Lexeme::DotDot,
+                Lexeme::Ident("0".to_string()),
+                Lexeme::Dot,
+                Lexeme::Ident("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_literals() {
+        assert_eq!(
+            lexemes(r#"12 1.5 2e3 "a\"bé""#),
+            vec![
+                Lexeme::Number(12.into()),
+                Lexeme::Number(serde_json::from_str("1.5").unwrap()),
+                Lexeme::Number(serde_json::from_str("2e3").unwrap()),
+                Lexeme::Str("a\"bé".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_operators() {
+        assert_eq!(
+            lexemes("== != < <= > >= ? @ && || ! ( ) [ ] ; , | + - * / % { } //"),
+            vec![
+                Lexeme::Eq,
+                Lexeme::Ne,
+                Lexeme::Lt,
+                Lexeme::Le,
+                Lexeme::Gt,
+                Lexeme::Ge,
+                Lexeme::Question,
+                Lexeme::At,
+                Lexeme::And,
+                Lexeme::Or,
+                Lexeme::Bang,
+                Lexeme::LParen,
+                Lexeme::RParen,
+                Lexeme::LBracket,
+                Lexeme::RBracket,
+                Lexeme::Semicolon,
+                Lexeme::Comma,
+                Lexeme::Pipe,
+                Lexeme::Plus,
+                Lexeme::Minus,
+                Lexeme::Star,
+                Lexeme::Slash,
+                Lexeme::Percent,
+                Lexeme::LBrace,
+                Lexeme::RBrace,
+                Lexeme::SlashSlash,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_variables() {
+        assert_eq!(
+            lexemes(". as $p_1 | $p_1.a"),
+            vec![
+                Lexeme::Dot,
+                Lexeme::Ident("as".to_string()),
+                Lexeme::Variable("p_1".to_string()),
+                Lexeme::Pipe,
+                Lexeme::Variable("p_1".to_string()),
+                Lexeme::Dot,
+                Lexeme::Ident("a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_escapes() {
+        assert_eq!(
+            lexemes(r#""\\ \/ \b\f\n\r\t \u00e9 \ud83d\ude00""#),
+            vec![Lexeme::Str("\\ / \u{8}\u{c}\n\r\t é 😀".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_interpolation() {
+        let spanned = tokenize(r#""a\(f("\(1)"))b" x"#).unwrap();
+        let spanned_at = |offset, lexeme| Spanned {
+            end: offset + 1,
+            offset,
+            lexeme,
+        };
+        assert_eq!(
+            spanned[0].lexeme,
+            Lexeme::Template(vec![
+                Fragment::Text("a".to_string()),
+                Fragment::Code(vec![
+                    spanned_at(4, Lexeme::Ident("f".to_string())),
+                    spanned_at(5, Lexeme::LParen),
+                    Spanned {
+                        lexeme: Lexeme::Template(vec![Fragment::Code(vec![
+                            spanned_at(9, Lexeme::Number(1.into())),
+                            spanned_at(10, Lexeme::RParen),
+                        ])]),
+                        offset: 6,
+                        end: 12,
+                    },
+                    spanned_at(12, Lexeme::RParen),
+                    spanned_at(13, Lexeme::RParen),
+                ]),
+                Fragment::Text("b".to_string()),
+            ])
+        );
+        assert_eq!(spanned[1], spanned_at(17, Lexeme::Ident("x".to_string())));
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert!(tokenize("$ a").is_err());
+        assert!(tokenize("a = b").is_err());
+        assert!(tokenize(r#""open"#).is_err());
+        assert!(tokenize(r#""\q""#).is_err());
+        assert!(tokenize(r#""\ud83d""#).is_err());
+        assert!(tokenize(r#""\u12""#).is_err());
+        assert!(tokenize("\"a\nb\"").is_err());
+        assert_eq!(
+            tokenize(r#""a \(1 + 2"#).unwrap_err().message,
+            "Unterminated interpolation"
+        );
+    }
+}
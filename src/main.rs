@@ -1,16 +1,31 @@
-use clap::{App, Arg};
+mod ast;
+mod builtins;
+mod filter;
+mod lexer;
+mod module;
+mod parser;
+mod patterns;
+mod scope;
+mod value;
+
+use ast::Expr;
+use clap::{App, Arg, ArgMatches};
+use filter::{eval, Options};
+use module::{search_path, Loader};
+use parser::{parse_query, ParseError};
+use scope::Scope;
 use serde_json::Value;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::BufReader;
 use std::io::Read;
 use std::num;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::result;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
-enum YajqError {
+pub enum YajqError {
     #[error("IO Error: {0}")]
     IO(#[from] io::Error),
 
@@ -20,11 +35,45 @@ enum YajqError {
     #[error("Filtering Error: {0}")]
     Filtering(String),
 
+    #[error("Filtering Error: Key {0} not in dict")]
+    MissingKey(String),
+
+    #[error("Index Error: index {index} is out of bounds for length {length} at {path}")]
+    IndexOutOfBounds {
+        path: String,
+        index: i64,
+        length: usize,
+    },
+
+    #[error("Arithmetic Error: {0}")]
+    Arithmetic(String),
+
+    #[error("Type Error: {0}")]
+    Type(String),
+
+    #[error("Regex Error: {0}")]
+    Regex(String),
+
+    #[error("Error: {}", raised_message(.0))]
+    Raised(Value),
+
     #[error("Parsing Error: {0}")]
     Parsing(#[from] num::ParseIntError),
+
+    #[error("Parse Error: {0}")]
+    ParseError(#[from] ParseError),
 }
 
-type Result<T> = result::Result<T, YajqError>;
+pub type Result<T> = result::Result<T, YajqError>;
+
+/// The message of an `error(...)` call: strings as they are, anything else as
+/// JSON.
+fn raised_message(value: &Value) -> String {
+    match value {
+        Value::String(message) => message.to_owned(),
+        value => format!("{} (not a string)", value),
+    }
+}
 
 fn main() {
     if let Err(e) = run() {
@@ -33,7 +82,39 @@ fn main() {
 }
 
 fn run() -> Result<()> {
-    let matches = App::new("YAJQ")
+    let matches = app().get_matches();
+    let options = Options {
+        max_depth: matches
+            .value_of("max-depth")
+            .map(str::parse::<usize>)
+            .transpose()?,
+        lenient: matches.is_present("lenient"),
+        collect: matches.is_present("collect"),
+    };
+    let expr = match matches.value_of("expression") {
+        Some(expr) => {
+            let libraries = matches.values_of("library").into_iter().flatten();
+            let mut loader = Loader::new(search_path(libraries.map(PathBuf::from).collect()));
+            parse_query(expr, &mut loader)?
+        }
+        None => Expr::Identity,
+    };
+    let scope = variables(&variable_flags(&matches))?;
+    let data = parse_data(matches.value_of("file"))?;
+    let compact = matches.is_present("compact");
+    eval(&expr, &data, &options, &scope, &mut |output| {
+        if compact {
+            println!("{}", serde_json::to_string(&output)?);
+        } else {
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        Ok(())
+    })
+}
+
+/// The command line options.
+fn app() -> App<'static, 'static> {
+    App::new("YAJQ")
         .version("1.0")
         .author("David Sternlicht <d1618033@gmail.com>")
         .about("Yet Another Json Query Language")
@@ -44,70 +125,121 @@ fn run() -> Result<()> {
                 .long("file")
                 .takes_value(true),
         )
-        .get_matches();
-    let data = parse_data(matches.value_of("file"))?;
-    let filtered = match matches.value_of("expression") {
-        Some(expr) => {
-            let tokens = parse_expression(expr);
-            filter(&data, tokens)?
-        }
-        None => data,
-    };
-    println!("{}", serde_json::to_string_pretty(&filtered)?);
-    Ok(())
+        .arg(
+            Arg::with_name("max-depth")
+                .value_name("DEPTH")
+                .long("max-depth")
+                .help("How many levels below the current value `..` searches")
+                .takes_value(true)
+                .validator(|depth| {
+                    depth
+                        .parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a number of levels", depth))
+                }),
+        )
+        .arg(
+            Arg::with_name("lenient").long("lenient").help(
+                "Missing keys yield null, or are left out of `*` results, instead of failing",
+            ),
+        )
+        .arg(
+            Arg::with_name("collect")
+                .long("collect")
+                .help("Gather the results of `*`, `*~`, `..` and `[?]` into arrays"),
+        )
+        .arg(variable_arg(
+            "arg",
+            "VALUE",
+            "Bind $NAME to the string VALUE",
+        ))
+        .arg(variable_arg(
+            "argjson",
+            "JSON",
+            "Bind $NAME to the parsed JSON",
+        ))
+        .arg(variable_arg(
+            "slurpfile",
+            "FILE",
+            "Bind $NAME to an array of the JSON values in FILE",
+        ))
+        .arg(variable_arg(
+            "rawfile",
+            "FILE",
+            "Bind $NAME to the contents of FILE as a string",
+        ))
+        .arg(
+            Arg::with_name("library")
+                .short("L")
+                .value_name("DIR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Search DIR for imported modules, before $YAJQ_LIB_PATH and ~/.yajq"),
+        )
+        .arg(
+            Arg::with_name("compact")
+                .short("c")
+                .long("compact")
+                .help("Print each output on a single line"),
+        )
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum Token<'a> {
-    Any,
-    Key(&'a str),
+/// A repeatable `--flag NAME VALUE` option binding the variable `$NAME`.
+fn variable_arg<'a>(flag: &'a str, value: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(flag)
+        .long(flag)
+        .value_names(&["NAME", value])
+        .number_of_values(2)
+        .multiple(true)
+        .help(help)
 }
 
-fn parse_expression(expression: &str) -> Vec<Token> {
-    expression
-        .split('.')
-        .into_iter()
-        .map(|element| match element {
-            "*" => Token::Any,
-            _ => Token::Key(element),
+/// The flags binding variables, from lowest to highest precedence when they
+/// bind the same name.
+const VARIABLE_FLAGS: &[&str] = &["arg", "argjson", "slurpfile", "rawfile"];
+
+/// The `(flag, name, value)` triples given with `--arg`, `--argjson`,
+/// `--slurpfile` and `--rawfile`, in the order of `VARIABLE_FLAGS`.
+fn variable_flags<'a>(matches: &'a ArgMatches) -> Vec<(&'a str, &'a str, &'a str)> {
+    VARIABLE_FLAGS
+        .iter()
+        .flat_map(|flag| {
+            let values: Vec<&str> = matches.values_of(flag).into_iter().flatten().collect();
+            triples(flag, &values)
         })
         .collect()
 }
 
-fn filter(data: &Value, tokens: Vec<Token>) -> Result<Value> {
-    if tokens.is_empty() {
-        Ok(data.to_owned())
-    } else {
-        return match tokens[0] {
-            Token::Any => match data {
-                Value::Array(array) => {
-                    let result: Result<Vec<Value>> = array
-                        .iter()
-                        .map(|element| filter(element, tokens[1..].to_vec()))
-                        .collect();
-                    Ok(Value::Array(result?))
+/// Pairs up the `NAME VALUE` values given with `flag`.
+fn triples<'a>(flag: &'a str, values: &[&'a str]) -> Vec<(&'a str, &'a str, &'a str)> {
+    values
+        .chunks(2)
+        .map(|pair| (flag, pair[0], pair[1]))
+        .collect()
+}
+
+/// Binds a variable for each `(flag, name, value)` triple; later triples
+/// shadow earlier ones.
+fn variables(flags: &[(&str, &str, &str)]) -> Result<Scope> {
+    flags
+        .iter()
+        .try_fold(Scope::default(), |scope, (flag, name, value)| {
+            let value = match *flag {
+                "arg" => Value::from(*value),
+                "argjson" => serde_json::from_str(value)?,
+                "slurpfile" => {
+                    let reader = BufReader::new(File::open(Path::new(value))?);
+                    let values = serde_json::Deserializer::from_reader(reader)
+                        .into_iter()
+                        .collect::<result::Result<Vec<Value>, _>>()?;
+                    Value::Array(values)
                 }
-                _ => Err(YajqError::Filtering(
-                    "Can't use * on non array".to_string(),
-                )),
-            },
-            Token::Key(key) => filter(
-                match data {
-                    Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => {
-                        Err(YajqError::Filtering(format!(
-                            "Unit can't be filtered for key {}",
-                            key
-                        )))
-                    }
-                    Value::Object(object) => Ok(object.get(key).ok_or_else(|| {
-                        YajqError::Filtering(format!("Key {} not in dict", key))
-                    })?),
-                    Value::Array(array) => Ok(&array[key.parse::<usize>()?]),
-                }?,
-                tokens[1..].to_vec(),
-            ),
-        };
-    }
+                "rawfile" => Value::from(fs::read_to_string(value)?),
+                _ => unreachable!("--{} doesn't bind variables", flag),
+            };
+            Ok(scope.bind(name, value))
+        })
 }
 
 fn parse_data(path: Option<&str>) -> Result<Value> {
@@ -128,67 +260,82 @@ fn parse_data(path: Option<&str>) -> Result<Value> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json::json;
+    use std::env;
+
+    fn bound(flags: &[(&str, &str, &str)], name: &str) -> Value {
+        variables(flags).unwrap().get(name).unwrap().to_owned()
+    }
 
     #[test]
-    fn test_parse_expression() {
+    fn test_variable_flags() {
+        let matches = app().get_matches_from(vec![
+            "yajq",
+            "--argjson",
+            "x",
+            "1",
+            "--arg",
+            "x",
+            "s",
+            "--arg",
+            "y",
+            "a",
+            "--arg",
+            "z",
+            "b",
+            ".",
+        ]);
+        // `--argjson` comes after `--arg` whatever the order on the command line.
         assert_eq!(
-            parse_expression("a.12.*.c"),
+            variable_flags(&matches),
             vec![
-                Token::Key("a"),
-                Token::Key("12"),
-                Token::Any,
-                Token::Key("c")
+                ("arg", "x", "s"),
+                ("arg", "y", "a"),
+                ("arg", "z", "b"),
+                ("argjson", "x", "1"),
             ]
         );
+        assert_eq!(
+            triples("arg", &["a", "1", "b", "2"]),
+            vec![("arg", "a", "1"), ("arg", "b", "2")]
+        );
     }
 
-    fn filter_(data: &str, expression: &str) -> Value {
-        filter(
-            &serde_json::from_str(data).unwrap(),
-            parse_expression(expression),
-        )
-        .unwrap()
-        .to_owned()
-    }
-    fn parse_data_(data: &str) -> Value {
-        serde_json::from_str(data).unwrap()
-    }
     #[test]
-    fn test_filter_simple() {
-        assert_eq!(filter_(r#"{"x": "value"}"#, "x"), parse_data_(r#""value""#))
-    }
-    #[test]
-    fn test_filter_multiple_keys() {
-        assert_eq!(
-            filter_(r#"{"x": {"y": "value"}}"#, "x.y"),
-            parse_data_(r#""value""#)
-        )
+    fn test_max_depth() {
+        assert!(app()
+            .get_matches_from_safe(vec!["yajq", "--max-depth", "abc", "."])
+            .unwrap_err()
+            .message
+            .contains("--max-depth <DEPTH>': abc is not a number of levels"));
     }
+
     #[test]
-    fn test_filter_index() {
+    fn test_variables() {
+        assert_eq!(bound(&[("arg", "x", "[1]")], "x"), json!("[1]"));
+        assert_eq!(bound(&[("argjson", "x", "[1]")], "x"), json!([1]));
         assert_eq!(
-            filter_(r#"{"x": ["value"]}"#, "x.0"),
-            parse_data_(r#""value""#)
-        )
-    }
-    #[test]
-    fn test_filter_star() {
+            bound(&[("arg", "x", "s"), ("argjson", "x", "1")], "x"),
+            json!(1)
+        );
+        assert!(variables(&[("argjson", "x", "[1")]).is_err());
+
+        let directory = env::temp_dir().join(format!("yajq-variables-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let file = directory.join("values.json");
+        fs::write(&file, "1 [2]\n{\"a\": 3}\n").unwrap();
+        let path = file.to_str().unwrap();
         assert_eq!(
-            filter_(
-                r#"{"x": [{"name": "value1"}, {"name": "value2"}]}"#,
-                "x.*.name"
-            ),
-            parse_data_(r#"["value1", "value2"]"#)
-        )
-    }
-    #[test]
-    fn test_filter_multiple_stars() {
+            bound(&[("slurpfile", "x", path)], "x"),
+            json!([1, [2], {"a": 3}])
+        );
         assert_eq!(
-            filter_(
-                r#"{"x": [[{"name": "value1"}], [{"name": "value2"}]]}"#,
-                "x.*.*.name"
-            ),
-            parse_data_(r#"[["value1"], ["value2"]]"#)
-        )
+            bound(&[("rawfile", "x", path)], "x"),
+            json!("1 [2]\n{\"a\": 3}\n")
+        );
+        fs::write(&file, "1 [2").unwrap();
+        assert!(variables(&[("slurpfile", "x", path)]).is_err());
+        assert!(variables(&[("rawfile", "x", "/nonexistent/yajq")]).is_err());
+        fs::remove_dir_all(&directory).unwrap();
     }
 }
@@ -0,0 +1,236 @@
+use crate::ast::Expr;
+use crate::parser::{parse_module, ParseError};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The extension of library files, added to module names that lack it.
+const EXTENSION: &str = "yajq";
+
+/// A parsed library file.
+#[derive(Clone, Debug)]
+pub struct Module {
+    /// Its definitions, as a chain of `Def`s and `Import`s around `.`
+    pub body: Rc<Expr>,
+    /// The names and arities of the functions it defines
+    pub functions: Vec<(String, usize)>,
+}
+
+/// Why a module couldn't be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// It couldn't be found or read, or imports itself; reported at the
+    /// import
+    Import(String),
+    /// It doesn't parse; reported where in the module
+    Parse(ParseError),
+}
+
+/// Finds, parses and caches the modules imported by a query.
+#[derive(Debug, Default)]
+pub struct Loader {
+    search_path: Vec<PathBuf>,
+    /// The files being loaded, outermost first, to detect import cycles
+    loading: Vec<PathBuf>,
+    loaded: HashMap<PathBuf, Module>,
+}
+
+impl Loader {
+    pub fn new(search_path: Vec<PathBuf>) -> Loader {
+        Loader {
+            search_path,
+            ..Loader::default()
+        }
+    }
+
+    /// Loads the module `name` imported from the file `from`, or from the
+    /// query itself. Names starting with `./` or `../` are relative to the
+    /// importing file; others are looked up in the search path.
+    pub fn load(&mut self, name: &str, from: Option<&Path>) -> Result<Module, LoadError> {
+        let path = self.resolve(name, from)?;
+        let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if let Some(module) = self.loaded.get(&key) {
+            return Ok(module.clone());
+        }
+        if let Some(start) = self.loading.iter().position(|loading| *loading == key) {
+            let cycle: Vec<_> = self.loading[start..]
+                .iter()
+                .chain(Some(&key))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(LoadError::Import(format!(
+                "Import cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        let source = fs::read_to_string(&path).map_err(|e| {
+            LoadError::Import(format!("Can't read module {}: {}", path.display(), e))
+        })?;
+        self.loading.push(key.clone());
+        let module = parse_module(&source, &path, self);
+        self.loading.pop();
+        let module = module.map_err(LoadError::Parse)?;
+        self.loaded.insert(key, module.clone());
+        Ok(module)
+    }
+
+    fn resolve(&self, name: &str, from: Option<&Path>) -> Result<PathBuf, LoadError> {
+        let mut file = PathBuf::from(name);
+        if file.extension().is_none() {
+            file.set_extension(EXTENSION);
+        }
+        let relative = name.starts_with("./") || name.starts_with("../");
+        let directories = if relative {
+            let directory = from
+                .and_then(Path::parent)
+                .unwrap_or_else(|| Path::new("."));
+            vec![directory.to_path_buf()]
+        } else {
+            self.search_path.clone()
+        };
+        directories
+            .iter()
+            .map(|directory| directory.join(&file))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                let searched: Vec<_> = directories
+                    .iter()
+                    .map(|directory| directory.display().to_string())
+                    .collect();
+                LoadError::Import(format!(
+                    "Module {:?} not found in [{}]",
+                    name,
+                    searched.join(", ")
+                ))
+            })
+    }
+}
+
+/// The directories searched for modules: those given with `-L`, then those
+/// in `$YAJQ_LIB_PATH`, then `~/.yajq`.
+pub fn search_path(libraries: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut path = libraries;
+    if let Some(lib_path) = env::var_os("YAJQ_LIB_PATH") {
+        path.extend(env::split_paths(&lib_path));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        path.push(PathBuf::from(home).join(".yajq"));
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::{filter, Options};
+    use crate::parser::parse_query;
+    use crate::scope::Scope;
+    use serde_json::{json, Value};
+
+    /// A fresh directory containing `files`.
+    fn library(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = env::temp_dir().join(format!("yajq-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for (file, source) in files {
+            fs::write(directory.join(file), source).unwrap();
+        }
+        directory
+    }
+
+    fn query(directory: &Path, expression: &str) -> Result<Vec<Value>, String> {
+        let mut loader = Loader::new(vec![directory.to_path_buf()]);
+        let expr = parse_query(expression, &mut loader).map_err(|e| e.to_string())?;
+        filter(&json!(3), &expr, &Options::default(), &Scope::default()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_import() {
+        let directory = library(
+            "import",
+            &[(
+                "math.yajq",
+                "def double: . * 2;\ndef quadruple: double | double;\n",
+            )],
+        );
+        assert_eq!(
+            query(&directory, r#"import "math" as m; m::quadruple, m::double"#),
+            Ok(vec![json!(12), json!(6)])
+        );
+        // Imported functions are only callable through their alias.
+        assert!(query(&directory, r#"import "math" as m; double"#).is_err());
+        assert_eq!(
+            query(&directory, r#"include "math"; quadruple"#),
+            Ok(vec![json!(12)])
+        );
+        // A query's own functions aren't visible to the module.
+        assert_eq!(
+            query(
+                &directory,
+                r#"def double: 0; import "math" as m; m::quadruple"#
+            ),
+            Ok(vec![json!(12)])
+        );
+    }
+
+    #[test]
+    fn test_nested_imports() {
+        let directory = library(
+            "nested",
+            &[
+                ("base.yajq", "def inc: . + 1;"),
+                (
+                    "derived.yajq",
+                    "import \"./base\" as base;\ninclude \"base\";\ndef twice: base::inc | inc;",
+                ),
+            ],
+        );
+        assert_eq!(
+            query(&directory, r#"import "derived" as d; d::twice, d::inc"#),
+            Ok(vec![json!(5), json!(4)])
+        );
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let directory = library(
+            "errors",
+            &[
+                ("a.yajq", "import \"b\" as b;\ndef a: b::b;"),
+                ("b.yajq", "import \"a\" as a;\ndef b: a::a;"),
+                ("broken.yajq", "def ok: 1;\ndef broken: (1;"),
+                ("query.yajq", "def f: 1;\n.a"),
+            ],
+        );
+        let error = query(&directory, r#"import "a" as a; a::a"#).unwrap_err();
+        assert!(error.starts_with("Import cycle: "), "{}", error);
+        assert!(error.contains("a.yajq -> "), "{}", error);
+        assert!(error.ends_with("b.yajq at line 1, column 8"), "{}", error);
+        let error = query(&directory, r#"import "broken" as b; 1"#).unwrap_err();
+        assert!(
+            error.starts_with("Expected ')', found ';' in "),
+            "{}",
+            error
+        );
+        assert!(
+            error.ends_with("broken.yajq at line 2, column 15"),
+            "{}",
+            error
+        );
+        let error = query(&directory, r#"import "query" as q; 1"#).unwrap_err();
+        assert!(
+            error.ends_with("query.yajq at line 2, column 1"),
+            "{}",
+            error
+        );
+        let error = query(&directory, "1 |\ninclude \"missing\"; 1").unwrap_err();
+        assert!(
+            error.starts_with("Module \"missing\" not found in ["),
+            "{}",
+            error
+        );
+        assert!(error.ends_with("] at line 2, column 9"), "{}", error);
+    }
+}
@@ -0,0 +1,1612 @@
+use crate::ast::{BinaryOp, Definition, Expr, Token, UnaryOp};
+use crate::builtins;
+use crate::lexer::{tokenize, Fragment, Lexeme, Spanned};
+use crate::module::{LoadError, Loader, Module};
+use serde_json::Value;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    /// The library file the error is in, if not the query itself
+    pub file: Option<String>,
+}
+
+impl ParseError {
+    pub fn new(message: String, source: &str, offset: usize) -> ParseError {
+        let before = &source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+            .chars()
+            .count()
+            + 1;
+        ParseError {
+            message,
+            line,
+            column,
+            file: None,
+        }
+    }
+
+    fn in_file(self, file: Option<&Path>) -> ParseError {
+        ParseError {
+            file: file.map(|file| file.display().to_string()),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file)?;
+        }
+        write!(f, " at line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Parses a query that imports nothing.
+#[cfg(test)]
+pub fn parse_expression(expression: &str) -> Result<Expr, ParseError> {
+    parse_query(expression, &mut Loader::default())
+}
+
+/// Parses a query, loading the modules it imports with `loader`.
+pub fn parse_query(query: &str, loader: &mut Loader) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        source: query,
+        tokens: tokenize(query)?,
+        position: 0,
+        functions: Vec::new(),
+        file: None,
+        loader,
+    };
+    let expr = parser.expression()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(lexeme) => Err(parser.error(format!("Unexpected {}", lexeme))),
+    }
+}
+
+/// Parses the library file at `path`: a sequence of `import`s, `include`s
+/// and `def`s.
+pub fn parse_module(source: &str, path: &Path, loader: &mut Loader) -> Result<Module, ParseError> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source).map_err(|e| e.in_file(Some(path)))?,
+        position: 0,
+        functions: Vec::new(),
+        file: Some(path),
+        loader,
+    };
+    let mut declarations = Vec::new();
+    while parser.peek().is_some() {
+        match parser.declaration()? {
+            Some(declaration) => declarations.push(declaration),
+            None => return Err(parser.unexpected("a definition")),
+        }
+    }
+    let body = declarations
+        .into_iter()
+        .rev()
+        .fold(Expr::Identity, |rest, declaration| declaration.apply(rest));
+    Ok(Module {
+        body: Rc::new(body),
+        functions: parser.functions,
+    })
+}
+
+/// A `def`, `import` or `include`, which applies to the rest of the
+/// expression or module it starts.
+enum Declaration {
+    Def(Rc<Definition>),
+    Import(Rc<Expr>, String),
+    Include(Rc<Expr>),
+}
+
+impl Declaration {
+    fn apply(self, rest: Expr) -> Expr {
+        match self {
+            Declaration::Def(definition) => Expr::Def(definition, Box::new(rest)),
+            Declaration::Import(module, alias) => Expr::Import(module, alias, Box::new(rest)),
+            Declaration::Include(module) => include(&module, rest),
+        }
+    }
+}
+
+/// The definitions of an included module, inlined around `rest`.
+fn include(module: &Expr, rest: Expr) -> Expr {
+    match module {
+        Expr::Def(definition, inner) => {
+            Expr::Def(definition.clone(), Box::new(include(inner, rest)))
+        }
+        Expr::Import(imported, alias, inner) => Expr::Import(
+            imported.clone(),
+            alias.clone(),
+            Box::new(include(inner, rest)),
+        ),
+        _ => rest,
+    }
+}
+
+/// Names that end an expression, and so can't be used as bare keys.
+const KEYWORDS: &[&str] = &["as", "def", "then", "elif", "else", "end"];
+
+/// Recursive descent parser; one method per precedence level, loosest first.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Spanned>,
+    position: usize,
+    /// The names and arities of the functions and parameters in scope, so
+    /// that bare names can be told apart from keys
+    functions: Vec<(String, usize)>,
+    /// The library file being parsed, if not the query itself
+    file: Option<&'a Path>,
+    loader: &'a mut Loader,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.tokens
+            .get(self.position)
+            .map(|spanned| &spanned.lexeme)
+    }
+
+    fn peek_at(&self, distance: usize) -> Option<&Lexeme> {
+        self.tokens
+            .get(self.position + distance)
+            .map(|spanned| &spanned.lexeme)
+    }
+
+    /// Whether the token `distance` ahead directly follows the one before it,
+    /// with no whitespace in between.
+    fn attached(&self, distance: usize) -> bool {
+        let position = self.position + distance;
+        match (
+            self.tokens.get(position.wrapping_sub(1)),
+            self.tokens.get(position),
+        ) {
+            (Some(previous), Some(next)) => previous.end == next.offset,
+            _ => false,
+        }
+    }
+
+    fn next(&mut self) -> Option<Lexeme> {
+        let lexeme = self.peek().cloned();
+        self.position += 1;
+        lexeme
+    }
+
+    fn eat(&mut self, expected: &Lexeme) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: &Lexeme) -> Result<(), ParseError> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("{}", expected)))
+        }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        self.error_at(self.position, message)
+    }
+
+    fn error_at(&self, position: usize, message: String) -> ParseError {
+        let offset = self
+            .tokens
+            .get(position)
+            .map_or(self.source.len(), |spanned| spanned.offset);
+        ParseError::new(message, self.source, offset).in_file(self.file)
+    }
+
+    fn unexpected(&self, wanted: &str) -> ParseError {
+        match self.peek() {
+            Some(lexeme) => self.error(format!("Expected {}, found {}", wanted, lexeme)),
+            None => self.error(format!("Expected {}, found end of input", wanted)),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        self.pipe()
+    }
+
+    fn pipe(&mut self) -> Result<Expr, ParseError> {
+        let outer = self.functions.len();
+        if let Some(declaration) = self.declaration()? {
+            let rest = self.pipe()?;
+            self.functions.truncate(outer);
+            return Ok(declaration.apply(rest));
+        }
+        let left = self.comma()?;
+        if self.eat(&Lexeme::Pipe) {
+            Ok(Expr::Pipe(Box::new(left), Box::new(self.pipe()?)))
+        } else {
+            Ok(left)
+        }
+    }
+
+    fn comma(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.alternative()?;
+        while self.eat(&Lexeme::Comma) {
+            left = Expr::Comma(Box::new(left), Box::new(self.alternative()?));
+        }
+        Ok(left)
+    }
+
+    fn alternative(&mut self) -> Result<Expr, ParseError> {
+        let left = self.or()?;
+        if self.eat(&Lexeme::SlashSlash) {
+            Ok(Expr::Binary(
+                BinaryOp::Alternative,
+                Box::new(left),
+                Box::new(self.alternative()?),
+            ))
+        } else {
+            Ok(left)
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.and()?;
+        while self.eat(&Lexeme::Or) {
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.comparison()?;
+        while self.eat(&Lexeme::And) {
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let left = self.additive()?;
+        let op = match self.peek() {
+            Some(Lexeme::Eq) => BinaryOp::Eq,
+            Some(Lexeme::Ne) => BinaryOp::Ne,
+            Some(Lexeme::Lt) => BinaryOp::Lt,
+            Some(Lexeme::Le) => BinaryOp::Le,
+            Some(Lexeme::Gt) => BinaryOp::Gt,
+            Some(Lexeme::Ge) => BinaryOp::Ge,
+            Some(Lexeme::Ident(name)) if name == "starts_with" => BinaryOp::StartsWith,
+            Some(Lexeme::Ident(name)) if name == "ends_with" => BinaryOp::EndsWith,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.additive()?)))
+    }
+
+    fn additive(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Lexeme::Plus) => BinaryOp::Add,
+                Some(Lexeme::Minus) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Lexeme::Star) => BinaryOp::Mul,
+                Some(Lexeme::Slash) => BinaryOp::Div,
+                Some(Lexeme::Percent) => BinaryOp::Rem,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat(&Lexeme::Bang) {
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+        } else if self.eat(&Lexeme::Minus) {
+            Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)))
+        } else {
+            self.term()
+        }
+    }
+
+    /// Parses a term, and the `as $name | body` after it if it binds a
+    /// variable; the body extends as far right as possible.
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let source = self.postfix()?;
+        if !self.eat_keyword("as") {
+            return Ok(source);
+        }
+        let name = self.variable()?;
+        self.expect(&Lexeme::Pipe)?;
+        Ok(Expr::Bind(Box::new(source), name, Box::new(self.pipe()?)))
+    }
+
+    fn variable(&mut self) -> Result<String, ParseError> {
+        match self.next() {
+            Some(Lexeme::Variable(name)) => Ok(name),
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a variable"))
+            }
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let (base, mut tokens) = self.primary()?;
+        self.segments(&mut tokens)?;
+        Ok(if tokens.is_empty() {
+            base
+        } else {
+            Expr::Path(Box::new(base), tokens)
+        })
+    }
+
+    /// Parses a term, returning it split into a base and any path tokens it
+    /// already started, so that `people` and `.people` share one representation.
+    fn primary(&mut self) -> Result<(Expr, Vec<Token>), ParseError> {
+        match self.peek() {
+            Some(Lexeme::Dot) => {
+                // A dot directly followed by a segment is left for `segments`
+                // to parse, so that `. * 2` stays a multiplication.
+                match self.peek_at(1) {
+                    Some(Lexeme::Ident(_))
+                    | Some(Lexeme::Star)
+                    | Some(Lexeme::Str(_))
+                    | Some(Lexeme::Minus)
+                    | Some(Lexeme::LBracket)
+                        if self.attached(1) => {}
+                    _ => self.position += 1,
+                }
+                Ok((Expr::Identity, vec![]))
+            }
+            Some(Lexeme::DotDot) => Ok((Expr::Identity, vec![])),
+            Some(Lexeme::At) => {
+                self.position += 1;
+                Ok((Expr::Identity, vec![]))
+            }
+            Some(Lexeme::Star) => {
+                self.position += 1;
+                Ok((Expr::Identity, vec![self.wildcard()]))
+            }
+            Some(Lexeme::LParen) => {
+                self.position += 1;
+                let expr = self.expression()?;
+                self.expect(&Lexeme::RParen)?;
+                Ok((expr, vec![]))
+            }
+            Some(Lexeme::Number(number)) => {
+                let literal = Expr::Literal(Value::Number(number.clone()));
+                self.position += 1;
+                Ok((literal, vec![]))
+            }
+            Some(Lexeme::Str(string)) => {
+                let literal = Expr::Literal(Value::String(string.clone()));
+                self.position += 1;
+                Ok((literal, vec![]))
+            }
+            Some(Lexeme::Template(fragments)) => {
+                let fragments = fragments.clone();
+                self.position += 1;
+                Ok((self.template(fragments)?, vec![]))
+            }
+            Some(Lexeme::LBracket) => {
+                self.position += 1;
+                if self.eat(&Lexeme::RBracket) {
+                    return Ok((Expr::Literal(Value::Array(vec![])), vec![]));
+                }
+                let expr = self.expression()?;
+                self.expect(&Lexeme::RBracket)?;
+                Ok((Expr::Array(Box::new(expr)), vec![]))
+            }
+            Some(Lexeme::Variable(name)) => {
+                let variable = Expr::Variable(name.clone());
+                self.position += 1;
+                Ok((variable, vec![]))
+            }
+            Some(Lexeme::LBrace) => {
+                self.position += 1;
+                Ok((self.object()?, vec![]))
+            }
+            Some(Lexeme::Ident(name)) if name == "if" => {
+                self.position += 1;
+                Ok((self.conditional()?, vec![]))
+            }
+            Some(Lexeme::Ident(name)) if name == "reduce" || name == "foreach" => {
+                let reduce = name == "reduce";
+                self.position += 1;
+                Ok((self.fold(reduce)?, vec![]))
+            }
+            Some(Lexeme::Ident(name)) if KEYWORDS.contains(&name.as_str()) => {
+                Err(self.unexpected("an expression"))
+            }
+            Some(Lexeme::Ident(name)) => {
+                let name = name.clone();
+                let start = self.position;
+                self.position += 1;
+                if let Some(function) = self.qualified() {
+                    let name = format!("{}::{}", name, function);
+                    let arguments = if self.eat(&Lexeme::LParen) {
+                        self.arguments()?
+                    } else {
+                        vec![]
+                    };
+                    return Ok((Expr::Call(name, arguments), vec![]));
+                }
+                if self.eat(&Lexeme::LParen) {
+                    let arguments = self.arguments()?;
+                    self.check_arity(&name, arguments.len(), start)?;
+                    return Ok((Expr::Call(name, arguments), vec![]));
+                }
+                // `name .key` would otherwise mean a call or a key depending
+                // on whether a function by that name exists.
+                if self.segment_next() && !self.attached(0) {
+                    return Err(self.error(format!(
+                        "Unexpected {} after a space following {}",
+                        self.peek().unwrap(),
+                        name
+                    )));
+                }
+                self.check_arity(&name, 0, start)?;
+                Ok(match name.as_str() {
+                    "null" => (Expr::Literal(Value::Null), vec![]),
+                    "true" => (Expr::Literal(Value::Bool(true)), vec![]),
+                    "false" => (Expr::Literal(Value::Bool(false)), vec![]),
+                    // A bare name is a call when a function by that name
+                    // exists, and a key otherwise; `.name` is always a key.
+                    // Built-ins directly followed by a segment stay keys, so
+                    // that paths like `values.0` keep their meaning.
+                    _ if self.is_defined(&name, 0) => (Expr::Call(name, vec![]), vec![]),
+                    _ if builtins::lookup(&name, 0).is_some() && !self.segment_follows() => {
+                        (Expr::Call(name, vec![]), vec![])
+                    }
+                    _ => (Expr::Identity, vec![Token::Key(name)]),
+                })
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    /// Parses the `.key`, `."key"`, `..key` and `[...]` segments following a
+    /// term, each of which may be marked optional with a trailing `?`.
+    fn segments(&mut self, tokens: &mut Vec<Token>) -> Result<(), ParseError> {
+        loop {
+            if let Some(token) = tokens.last_mut() {
+                if !matches!(token, Token::Optional(_)) && self.eat(&Lexeme::Question) {
+                    *token = Token::Optional(Box::new(token.clone()));
+                }
+            }
+            if self.eat(&Lexeme::DotDot) {
+                tokens.push(self.recurse()?);
+                continue;
+            }
+            if self.eat(&Lexeme::Dot) {
+                if !self.eat(&Lexeme::LBracket) {
+                    tokens.push(self.segment()?);
+                    continue;
+                }
+            } else if !self.eat(&Lexeme::LBracket) {
+                return Ok(());
+            }
+            tokens.push(self.bracket()?);
+        }
+    }
+
+    fn segment(&mut self) -> Result<Token, ParseError> {
+        match self.next() {
+            Some(Lexeme::Star) => Ok(self.wildcard()),
+            Some(Lexeme::Ident(name)) | Some(Lexeme::Str(name)) => Ok(Token::Key(name)),
+            Some(Lexeme::Minus) => match self.next() {
+                Some(Lexeme::Ident(digits)) if digits.parse::<i64>().is_ok() => {
+                    Ok(Token::Index(-digits.parse::<i64>().unwrap()))
+                }
+                _ => {
+                    self.position -= 1;
+                    Err(self.unexpected("an index"))
+                }
+            },
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a key or '*'"))
+            }
+        }
+    }
+
+    /// Parses the segment searched for after `..`.
+    fn recurse(&mut self) -> Result<Token, ParseError> {
+        let token = if self.eat(&Lexeme::LBracket) {
+            self.bracket()?
+        } else {
+            self.segment()?
+        };
+        match token {
+            Token::Any | Token::Key(_) | Token::Index(_) => Ok(Token::Recurse(Box::new(token))),
+            _ => Err(self.error(format!("Can't search recursively for {}", token))),
+        }
+    }
+
+    /// Finishes a wildcard after its `*`, which a `~` turns into `*~`.
+    fn wildcard(&mut self) -> Token {
+        if self.eat(&Lexeme::Tilde) {
+            Token::Entries
+        } else {
+            Token::Any
+        }
+    }
+
+    /// Parses the inside of `[...]` after the opening bracket.
+    fn bracket(&mut self) -> Result<Token, ParseError> {
+        if self.eat(&Lexeme::Question) {
+            let condition = self.expression()?;
+            self.expect(&Lexeme::RBracket)?;
+            return Ok(Token::Filter(Box::new(condition)));
+        }
+        if self.eat(&Lexeme::RBracket) {
+            return Ok(Token::Iterate);
+        }
+        let start = self.position;
+        if let Some(token) = self.literal_bracket()? {
+            return Ok(token);
+        }
+        // Anything else is an expression computing the key or index.
+        self.position = start;
+        if let Some(Lexeme::Number(_)) = self.peek() {
+            if self.peek_at(1) == Some(&Lexeme::RBracket) {
+                return Err(self.unexpected("a quoted key or an index"));
+            }
+        }
+        let expr = self.expression()?;
+        self.expect(&Lexeme::RBracket)?;
+        Ok(Token::Dynamic(Box::new(expr)))
+    }
+
+    /// Parses a `["key"]`, `[index]` or `[start:stop:step]` bracket, or
+    /// returns `None` if the bracket holds some other expression.
+    fn literal_bracket(&mut self) -> Result<Option<Token>, ParseError> {
+        if let Some(Lexeme::Str(key)) = self.peek() {
+            let key = key.clone();
+            self.position += 1;
+            return Ok(if self.eat(&Lexeme::RBracket) {
+                Some(Token::Key(key))
+            } else {
+                None
+            });
+        }
+        let start = match self.integer() {
+            Ok(start) => start,
+            Err(_) => return Ok(None),
+        };
+        if self.eat(&Lexeme::Colon) {
+            let stop = self.integer()?;
+            let step = if self.eat(&Lexeme::Colon) {
+                self.integer()?
+            } else {
+                None
+            };
+            self.expect(&Lexeme::RBracket)?;
+            return Ok(Some(Token::Slice(start, stop, step)));
+        }
+        Ok(match start {
+            Some(index) if self.eat(&Lexeme::RBracket) => Some(Token::Index(index)),
+            _ => None,
+        })
+    }
+
+    /// Parses an optional, possibly negative, integer such as a slice bound.
+    fn integer(&mut self) -> Result<Option<i64>, ParseError> {
+        let negative = self.eat(&Lexeme::Minus);
+        match self.peek() {
+            Some(Lexeme::Number(number)) if number.is_i64() => {
+                let value = number.as_i64().unwrap();
+                self.position += 1;
+                Ok(Some(if negative { -value } else { value }))
+            }
+            _ if negative => Err(self.unexpected("an integer")),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parses a `def`, `import` or `include` if one comes next, making the
+    /// functions it declares visible to the rest of the expression.
+    fn declaration(&mut self) -> Result<Option<Declaration>, ParseError> {
+        if self.eat_keyword("def") {
+            return Ok(Some(Declaration::Def(self.definition()?)));
+        }
+        let (include, name) = match (self.peek(), self.peek_at(1)) {
+            (Some(Lexeme::Ident(keyword)), Some(Lexeme::Str(name)))
+                if keyword == "import" || keyword == "include" =>
+            {
+                (keyword == "include", name.clone())
+            }
+            _ => return Ok(None),
+        };
+        // Errors finding the module point at its name.
+        self.position += 1;
+        let module = match self.loader.load(&name, self.file) {
+            Ok(module) => module,
+            Err(LoadError::Import(message)) => return Err(self.error(message)),
+            Err(LoadError::Parse(error)) => return Err(error),
+        };
+        self.position += 1;
+        let declaration = if include {
+            self.functions.extend(module.functions);
+            Declaration::Include(module.body)
+        } else {
+            self.keyword("as")?;
+            let alias = match self.next() {
+                Some(Lexeme::Ident(alias)) if !KEYWORDS.contains(&alias.as_str()) => alias,
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a module name"));
+                }
+            };
+            Declaration::Import(module.body, alias)
+        };
+        self.expect(&Lexeme::Semicolon)?;
+        Ok(Some(declaration))
+    }
+
+    /// Parses the rest of `def name(params): body;` after the `def`, leaving
+    /// the function visible to what follows.
+    fn definition(&mut self) -> Result<Rc<Definition>, ParseError> {
+        let name = match self.next() {
+            Some(Lexeme::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => name,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected("a function name"));
+            }
+        };
+        let mut params = Vec::new();
+        let mut values = Vec::new();
+        if self.eat(&Lexeme::LParen) {
+            loop {
+                match self.next() {
+                    Some(Lexeme::Ident(param)) => params.push(param),
+                    Some(Lexeme::Variable(param)) => {
+                        params.push(param.clone());
+                        values.push(param);
+                    }
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.unexpected("a parameter"));
+                    }
+                }
+                if !self.eat(&Lexeme::Semicolon) {
+                    break;
+                }
+            }
+            self.expect(&Lexeme::RParen)?;
+        }
+        self.expect(&Lexeme::Colon)?;
+        // The function is visible in its own body, to allow recursion, and
+        // in the rest of the expression; its parameters only in its body.
+        let outer = self.functions.len();
+        self.functions.push((name.clone(), params.len()));
+        self.functions
+            .extend(params.iter().map(|param| (param.clone(), 0)));
+        let mut body = self.expression()?;
+        self.functions.truncate(outer + 1);
+        self.expect(&Lexeme::Semicolon)?;
+        for value in values.into_iter().rev() {
+            let param = Expr::Call(value.clone(), vec![]);
+            body = Expr::Bind(Box::new(param), value, Box::new(body));
+        }
+        Ok(Rc::new(Definition { name, params, body }))
+    }
+
+    /// Parses the `::name` of a call to an imported function, which must be
+    /// written without spaces.
+    fn qualified(&mut self) -> Option<String> {
+        match (self.peek(), self.peek_at(1), self.peek_at(2)) {
+            (Some(Lexeme::Colon), Some(Lexeme::Colon), Some(Lexeme::Ident(function)))
+                if self.attached(0) && self.attached(1) && self.attached(2) =>
+            {
+                let function = function.clone();
+                self.position += 3;
+                Some(function)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a function or parameter is in scope, as opposed to a built-in.
+    fn is_defined(&self, name: &str, arity: usize) -> bool {
+        self.functions
+            .iter()
+            .any(|(function, params)| function == name && *params == arity)
+    }
+
+    /// Fails when `name` is a function defined with arities other than
+    /// `arity`, rather than reading a key or reporting an unknown function.
+    fn check_arity(&self, name: &str, arity: usize, start: usize) -> Result<(), ParseError> {
+        if self.is_defined(name, arity) || builtins::lookup(name, arity).is_some() {
+            return Ok(());
+        }
+        let mut arities: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|(function, _)| function == name)
+            .map(|(_, params)| *params)
+            .collect();
+        if arities.is_empty() {
+            return Ok(());
+        }
+        arities.sort_unstable();
+        arities.dedup();
+        let expected: Vec<_> = arities.iter().map(usize::to_string).collect();
+        Err(self.error_at(
+            start,
+            format!(
+                "Wrong number of arguments for {}: expected {}, found {}",
+                name,
+                expected.join(" or "),
+                arity
+            ),
+        ))
+    }
+
+    /// Whether a `.key`, `..key` or `[...]` segment comes next.
+    fn segment_next(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Lexeme::Dot) | Some(Lexeme::DotDot) | Some(Lexeme::LBracket)
+        )
+    }
+
+    /// Whether a `.key`, `..key` or `[...]` segment directly follows.
+    fn segment_follows(&self) -> bool {
+        self.segment_next() && self.attached(0)
+    }
+
+    /// Parses the rest of `if cond then a elif cond2 then b else c end` after
+    /// the `if`; without an `else`, the input is output unchanged.
+    fn conditional(&mut self) -> Result<Expr, ParseError> {
+        let condition = self.expression()?;
+        self.keyword("then")?;
+        let then = self.expression()?;
+        let otherwise = if self.eat_keyword("elif") {
+            return Ok(Expr::If(
+                Box::new(condition),
+                Box::new(then),
+                Box::new(self.conditional()?),
+            ));
+        } else if self.eat_keyword("else") {
+            self.expression()?
+        } else {
+            Expr::Identity
+        };
+        self.keyword("end")?;
+        Ok(Expr::If(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    /// Parses the rest of `reduce source as $x (init; update)` or
+    /// `foreach source as $x (init; update; extract)` after the keyword.
+    fn fold(&mut self, reduce: bool) -> Result<Expr, ParseError> {
+        let source = Box::new(self.postfix()?);
+        self.keyword("as")?;
+        let name = self.variable()?;
+        self.expect(&Lexeme::LParen)?;
+        let init = Box::new(self.expression()?);
+        self.expect(&Lexeme::Semicolon)?;
+        let update = Box::new(self.expression()?);
+        if reduce {
+            self.expect(&Lexeme::RParen)?;
+            return Ok(Expr::Reduce(source, name, init, update));
+        }
+        let extract = if self.eat(&Lexeme::Semicolon) {
+            Some(Box::new(self.expression()?))
+        } else {
+            None
+        };
+        self.expect(&Lexeme::RParen)?;
+        Ok(Expr::Foreach(source, name, init, update, extract))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Lexeme::Ident(name)) if name == keyword => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", keyword)))
+        }
+    }
+
+    /// Parses the entries of `{...}` after the opening brace: `key: value`,
+    /// `"key": value`, `(expr): value`, or just `key` for `key: .key`.
+    fn object(&mut self) -> Result<Expr, ParseError> {
+        let mut entries = Vec::new();
+        if self.eat(&Lexeme::RBrace) {
+            return Ok(Expr::Object(entries));
+        }
+        loop {
+            let entry = match self.next() {
+                Some(Lexeme::Ident(name)) | Some(Lexeme::Str(name)) => {
+                    let key = Expr::Literal(Value::from(name.as_str()));
+                    if self.eat(&Lexeme::Colon) {
+                        (key, self.alternative()?)
+                    } else {
+                        (
+                            key,
+                            Expr::Path(Box::new(Expr::Identity), vec![Token::Key(name)]),
+                        )
+                    }
+                }
+                Some(Lexeme::LParen) => {
+                    let key = self.expression()?;
+                    self.expect(&Lexeme::RParen)?;
+                    self.expect(&Lexeme::Colon)?;
+                    (key, self.alternative()?)
+                }
+                Some(Lexeme::Template(fragments)) => {
+                    let key = self.template(fragments)?;
+                    self.expect(&Lexeme::Colon)?;
+                    (key, self.alternative()?)
+                }
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("an object key"));
+                }
+            };
+            entries.push(entry);
+            if self.eat(&Lexeme::RBrace) {
+                return Ok(Expr::Object(entries));
+            }
+            if !self.eat(&Lexeme::Comma) {
+                return Err(self.unexpected("',' or '}'"));
+            }
+        }
+    }
+
+    /// Parses the interpolated expressions of a string literal, each followed
+    /// by the `)` closing it.
+    fn template(&mut self, fragments: Vec<Fragment>) -> Result<Expr, ParseError> {
+        let mut parts = Vec::new();
+        for fragment in fragments {
+            match fragment {
+                Fragment::Text(text) => parts.push(Expr::Literal(Value::String(text))),
+                Fragment::Code(tokens) => {
+                    let tokens = mem::replace(&mut self.tokens, tokens);
+                    let position = mem::replace(&mut self.position, 0);
+                    let part = self.expression().and_then(|part| {
+                        self.expect(&Lexeme::RParen)?;
+                        Ok(part)
+                    });
+                    self.tokens = tokens;
+                    self.position = position;
+                    parts.push(part?);
+                }
+            }
+        }
+        Ok(Expr::Template(parts))
+    }
+
+    /// Parses `;`-separated call arguments after the opening parenthesis.
+    fn arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut arguments = vec![self.expression()?];
+        while self.eat(&Lexeme::Semicolon) {
+            arguments.push(self.expression()?);
+        }
+        self.expect(&Lexeme::RParen)?;
+        Ok(arguments)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(name: &str) -> Token {
+        Token::Key(name.to_string())
+    }
+
+    fn path(tokens: Vec<Token>) -> Expr {
+        Expr::Path(Box::new(Expr::Identity), tokens)
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(
+            parse_expression("a.12.*.c").unwrap(),
+            path(vec![key("a"), key("12"), Token::Any, key("c")])
+        );
+    }
+
+    #[test]
+    fn test_parse_leading_dot() {
+        assert_eq!(parse_expression(".").unwrap(), Expr::Identity);
+        assert_eq!(
+            parse_expression(".a.*").unwrap(),
+            path(vec![key("a"), Token::Any])
+        );
+        assert_eq!(
+            parse_expression("*.name").unwrap(),
+            path(vec![Token::Any, key("name")])
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_and_bracketed_keys() {
+        assert_eq!(
+            parse_expression(r#"people["first.name"]"#).unwrap(),
+            path(vec![key("people"), key("first.name")])
+        );
+        assert_eq!(
+            parse_expression(r#"a."weird key".b"#).unwrap(),
+            path(vec![key("a"), key("weird key"), key("b")])
+        );
+        assert_eq!(
+            parse_expression(r#"."k8s.io/name""#).unwrap(),
+            path(vec![key("k8s.io/name")])
+        );
+        assert_eq!(
+            parse_expression(r#".["\u00e9\n"]"#).unwrap(),
+            path(vec![key("\u{e9}\n")])
+        );
+    }
+
+    #[test]
+    fn test_parse_keys_needing_quotes() {
+        // Bare keys are identifiers, so `-`, `/` and `:` end them; keys
+        // containing those must be quoted.
+        assert_eq!(
+            parse_expression("headers.Content-Type").unwrap(),
+            Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(path(vec![key("headers"), key("Content")])),
+                Box::new(path(vec![key("Type")]))
+            )
+        );
+        assert_eq!(
+            parse_expression("a.k8s/io").unwrap(),
+            Expr::Binary(
+                BinaryOp::Div,
+                Box::new(path(vec![key("a"), key("k8s")])),
+                Box::new(path(vec![key("io")]))
+            )
+        );
+        assert!(parse_expression("a.b:c").is_err());
+        assert_eq!(
+            parse_expression(r#"headers."Content-Type", a."k8s/io", a["b:c"]"#).unwrap(),
+            Expr::Comma(
+                Box::new(Expr::Comma(
+                    Box::new(path(vec![key("headers"), key("Content-Type")])),
+                    Box::new(path(vec![key("a"), key("k8s/io")]))
+                )),
+                Box::new(path(vec![key("a"), key("b:c")]))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_index_is_not_a_key() {
+        assert_eq!(
+            parse_expression(r#"a[0]["0"].[1]"#).unwrap(),
+            path(vec![key("a"), Token::Index(0), key("0"), Token::Index(1)])
+        );
+        assert!(parse_expression("a[1.5]").is_err());
+        assert_eq!(
+            parse_expression("a[b][$i]").unwrap(),
+            path(vec![
+                key("a"),
+                Token::Dynamic(Box::new(path(vec![key("b")]))),
+                Token::Dynamic(Box::new(Expr::Variable("i".to_string())))
+            ])
+        );
+        assert!(parse_expression("a[0").is_err());
+    }
+
+    #[test]
+    fn test_parse_negative_index_and_slices() {
+        assert_eq!(
+            parse_expression("people.-1").unwrap(),
+            path(vec![key("people"), Token::Index(-1)])
+        );
+        assert_eq!(
+            parse_expression("people[-1]").unwrap(),
+            path(vec![key("people"), Token::Index(-1)])
+        );
+        assert_eq!(
+            parse_expression("people[1:3]").unwrap(),
+            path(vec![key("people"), Token::Slice(Some(1), Some(3), None)])
+        );
+        assert_eq!(
+            parse_expression("people[::2]").unwrap(),
+            path(vec![key("people"), Token::Slice(None, None, Some(2))])
+        );
+        assert_eq!(
+            parse_expression(".[-2:]").unwrap(),
+            path(vec![Token::Slice(Some(-2), None, None)])
+        );
+        assert!(parse_expression("a[-]").is_err());
+        assert!(parse_expression("a.-b").is_err());
+        assert!(parse_expression("a[1:2:3:4]").is_err());
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        assert_eq!(
+            parse_expression("config.*~.key").unwrap(),
+            path(vec![key("config"), Token::Entries, key("key")])
+        );
+        assert_eq!(parse_expression("*~").unwrap(), path(vec![Token::Entries]));
+        assert!(parse_expression("config.~").is_err());
+    }
+
+    #[test]
+    fn test_parse_recursive_descent() {
+        let recurse = |token| Token::Recurse(Box::new(token));
+        assert_eq!(
+            parse_expression("..id").unwrap(),
+            path(vec![recurse(key("id"))])
+        );
+        assert_eq!(
+            parse_expression("a..*.b").unwrap(),
+            path(vec![key("a"), recurse(Token::Any), key("b")])
+        );
+        assert_eq!(
+            parse_expression(r#"..["a b"]..[0]"#).unwrap(),
+            path(vec![recurse(key("a b")), recurse(Token::Index(0))])
+        );
+        assert!(parse_expression("..").is_err());
+        assert!(parse_expression("..*~").is_err());
+        assert!(parse_expression("..[1:]").is_err());
+    }
+
+    #[test]
+    fn test_parse_predicates() {
+        let email = || Expr::Path(Box::new(Expr::Identity), vec![key("email")]);
+        assert_eq!(
+            parse_expression(r#"people[?(@.email ends_with "company.com")]"#).unwrap(),
+            path(vec![
+                key("people"),
+                Token::Filter(Box::new(Expr::Binary(
+                    BinaryOp::EndsWith,
+                    Box::new(email()),
+                    Box::new(Expr::Literal(Value::from("company.com"))),
+                )))
+            ])
+        );
+        assert_eq!(
+            parse_expression("a[?@.email].b").unwrap(),
+            path(vec![key("a"), Token::Filter(Box::new(email())), key("b")])
+        );
+        assert_eq!(
+            parse_expression("a <= 1").unwrap(),
+            Expr::Binary(
+                BinaryOp::Le,
+                Box::new(path(vec![key("a")])),
+                Box::new(Expr::Literal(Value::from(1)))
+            )
+        );
+        assert!(parse_expression("a[?]").is_err());
+        assert!(parse_expression("a[?(@.b]").is_err());
+        assert!(parse_expression("a < b < c").is_err());
+    }
+
+    #[test]
+    fn test_parse_optional_segments() {
+        let optional = |token| Token::Optional(Box::new(token));
+        assert_eq!(
+            parse_expression("a?.b").unwrap(),
+            path(vec![optional(key("a")), key("b")])
+        );
+        assert_eq!(
+            parse_expression(r#".a.b?[0]?.*?["c"]"#).unwrap(),
+            path(vec![
+                key("a"),
+                optional(key("b")),
+                optional(Token::Index(0)),
+                optional(Token::Any),
+                key("c")
+            ])
+        );
+        assert!(parse_expression("a??").is_err());
+        assert!(parse_expression("1?").is_err());
+    }
+
+    #[test]
+    fn test_parse_pipes() {
+        assert_eq!(
+            parse_expression("people | .[0] | .email").unwrap(),
+            Expr::Pipe(
+                Box::new(path(vec![key("people")])),
+                Box::new(Expr::Pipe(
+                    Box::new(path(vec![Token::Index(0)])),
+                    Box::new(path(vec![key("email")]))
+                ))
+            )
+        );
+        assert_eq!(
+            parse_expression("a, b == c | d[]").unwrap(),
+            Expr::Pipe(
+                Box::new(Expr::Comma(
+                    Box::new(path(vec![key("a")])),
+                    Box::new(Expr::Binary(
+                        BinaryOp::Eq,
+                        Box::new(path(vec![key("b")])),
+                        Box::new(path(vec![key("c")]))
+                    ))
+                )),
+                Box::new(path(vec![key("d"), Token::Iterate]))
+            )
+        );
+        assert!(parse_expression("a |").is_err());
+        assert!(parse_expression("| a").is_err());
+    }
+
+    #[test]
+    fn test_parse_literals() {
+        assert_eq!(
+            parse_expression("null").unwrap(),
+            Expr::Literal(Value::Null)
+        );
+        assert_eq!(
+            parse_expression("true").unwrap(),
+            Expr::Literal(Value::Bool(true))
+        );
+        assert_eq!(
+            parse_expression("42").unwrap(),
+            Expr::Literal(Value::from(42))
+        );
+        assert_eq!(
+            parse_expression(r#""a.b""#).unwrap(),
+            Expr::Literal(Value::from("a.b"))
+        );
+    }
+
+    #[test]
+    fn test_parse_interpolation() {
+        assert_eq!(
+            parse_expression(r#""\(name) (\(1 + (2)))""#).unwrap(),
+            Expr::Template(vec![
+                path(vec![key("name")]),
+                Expr::Literal(Value::from(" (")),
+                Expr::Binary(
+                    BinaryOp::Add,
+                    Box::new(Expr::Literal(Value::from(1))),
+                    Box::new(Expr::Literal(Value::from(2)))
+                ),
+                Expr::Literal(Value::from(")")),
+            ])
+        );
+        let error = parse_expression(r#""a \(1 +)""#).unwrap_err();
+        assert_eq!(error.message, "Expected an expression, found ')'");
+        assert_eq!((error.line, error.column), (1, 9));
+        assert!(parse_expression(r#""\()""#).is_err());
+        assert!(parse_expression(r#""\(1 2)""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_operator_precedence() {
+        assert_eq!(
+            parse_expression("!a == 1 || b && c").unwrap(),
+            Expr::Binary(
+                BinaryOp::Or,
+                Box::new(Expr::Binary(
+                    BinaryOp::Eq,
+                    Box::new(Expr::Unary(UnaryOp::Not, Box::new(path(vec![key("a")])))),
+                    Box::new(Expr::Literal(Value::from(1))),
+                )),
+                Box::new(Expr::Binary(
+                    BinaryOp::And,
+                    Box::new(path(vec![key("b")])),
+                    Box::new(path(vec![key("c")])),
+                )),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_arithmetic() {
+        let number = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            parse_expression("1 + 2 * -a - 3 % 2 < 4").unwrap(),
+            Expr::Binary(
+                BinaryOp::Lt,
+                Box::new(Expr::Binary(
+                    BinaryOp::Sub,
+                    Box::new(Expr::Binary(
+                        BinaryOp::Add,
+                        number(1),
+                        Box::new(Expr::Binary(
+                            BinaryOp::Mul,
+                            number(2),
+                            Box::new(Expr::Unary(UnaryOp::Neg, Box::new(path(vec![key("a")])))),
+                        )),
+                    )),
+                    Box::new(Expr::Binary(BinaryOp::Rem, number(3), number(2))),
+                )),
+                number(4),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_arithmetic_on_identity() {
+        assert_eq!(
+            parse_expression(". * 2").unwrap(),
+            Expr::Binary(
+                BinaryOp::Mul,
+                Box::new(Expr::Identity),
+                Box::new(Expr::Literal(Value::from(2)))
+            )
+        );
+        assert_eq!(
+            parse_expression(". - 1").unwrap(),
+            Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(Expr::Identity),
+                Box::new(Expr::Literal(Value::from(1)))
+            )
+        );
+        assert_eq!(
+            parse_expression(".-1").unwrap(),
+            path(vec![Token::Index(-1)])
+        );
+        assert_eq!(
+            parse_expression("a.0-1").unwrap(),
+            Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(path(vec![key("a"), key("0")])),
+                Box::new(Expr::Literal(Value::from(1)))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_construction() {
+        let string = |s: &str| Expr::Literal(Value::from(s));
+        assert_eq!(
+            parse_expression(r#"{name, "e-mail": .email, (.id): [.a, 1]}"#).unwrap(),
+            Expr::Object(vec![
+                (string("name"), path(vec![key("name")])),
+                (string("e-mail"), path(vec![key("email")])),
+                (
+                    path(vec![key("id")]),
+                    Expr::Array(Box::new(Expr::Comma(
+                        Box::new(path(vec![key("a")])),
+                        Box::new(Expr::Literal(Value::from(1)))
+                    )))
+                ),
+            ])
+        );
+        assert_eq!(
+            parse_expression("[][0], {}").unwrap(),
+            Expr::Comma(
+                Box::new(Expr::Path(
+                    Box::new(Expr::Literal(Value::Array(vec![]))),
+                    vec![Token::Index(0)]
+                )),
+                Box::new(Expr::Object(vec![]))
+            )
+        );
+        assert!(parse_expression("{a: 1, b}").is_ok());
+        assert!(parse_expression("{a: 1 b: 2}").is_err());
+        assert!(parse_expression("{1: 2}").is_err());
+        assert!(parse_expression("[1").is_err());
+    }
+
+    #[test]
+    fn test_parse_alternative() {
+        assert_eq!(
+            parse_expression("a // b // 1, c").unwrap(),
+            Expr::Comma(
+                Box::new(Expr::Binary(
+                    BinaryOp::Alternative,
+                    Box::new(path(vec![key("a")])),
+                    Box::new(Expr::Binary(
+                        BinaryOp::Alternative,
+                        Box::new(path(vec![key("b")])),
+                        Box::new(Expr::Literal(Value::from(1)))
+                    ))
+                )),
+                Box::new(path(vec![key("c")]))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_conditional() {
+        let number = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            parse_expression("if a then 1 elif b then 2 else 3 end").unwrap(),
+            Expr::If(
+                Box::new(path(vec![key("a")])),
+                number(1),
+                Box::new(Expr::If(
+                    Box::new(path(vec![key("b")])),
+                    number(2),
+                    number(3)
+                ))
+            )
+        );
+        assert_eq!(
+            parse_expression("if a then 1 end.x").unwrap(),
+            Expr::Path(
+                Box::new(Expr::If(
+                    Box::new(path(vec![key("a")])),
+                    number(1),
+                    Box::new(Expr::Identity)
+                )),
+                vec![key("x")]
+            )
+        );
+        assert_eq!(
+            parse_expression("if a then else 1 end")
+                .unwrap_err()
+                .to_string(),
+            "Expected an expression, found identifier else at line 1, column 11"
+        );
+        assert!(parse_expression("if a then 1").is_err());
+        assert!(parse_expression("if a 1 end").is_err());
+    }
+
+    #[test]
+    fn test_parse_bindings() {
+        assert_eq!(
+            parse_expression(". as $p | $p.name, 1 + a as $x | $x").unwrap(),
+            Expr::Bind(
+                Box::new(Expr::Identity),
+                "p".to_string(),
+                Box::new(Expr::Comma(
+                    Box::new(Expr::Path(
+                        Box::new(Expr::Variable("p".to_string())),
+                        vec![key("name")]
+                    )),
+                    Box::new(Expr::Binary(
+                        BinaryOp::Add,
+                        Box::new(Expr::Literal(Value::from(1))),
+                        Box::new(Expr::Bind(
+                            Box::new(path(vec![key("a")])),
+                            "x".to_string(),
+                            Box::new(Expr::Variable("x".to_string()))
+                        ))
+                    ))
+                ))
+            )
+        );
+        assert!(parse_expression(". as p | p").is_err());
+        assert!(parse_expression(". as $p").is_err());
+    }
+
+    #[test]
+    fn test_parse_folds() {
+        let number = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        let items = || Box::new(path(vec![key("items"), Token::Iterate]));
+        let sum = || {
+            Box::new(Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Identity),
+                Box::new(Expr::Variable("x".to_string())),
+            ))
+        };
+        assert_eq!(
+            parse_expression("reduce items[] as $x (0; . + $x)").unwrap(),
+            Expr::Reduce(items(), "x".to_string(), number(0), sum())
+        );
+        assert_eq!(
+            parse_expression("foreach items[] as $x (0; . + $x; [$x, .])").unwrap(),
+            Expr::Foreach(
+                items(),
+                "x".to_string(),
+                number(0),
+                sum(),
+                Some(Box::new(Expr::Array(Box::new(Expr::Comma(
+                    Box::new(Expr::Variable("x".to_string())),
+                    Box::new(Expr::Identity)
+                )))))
+            )
+        );
+        assert_eq!(
+            parse_expression("foreach items[] as $x (0; . + $x)").unwrap(),
+            Expr::Foreach(items(), "x".to_string(), number(0), sum(), None)
+        );
+        assert!(parse_expression("reduce items[] as $x (0; .; .)").is_err());
+        assert!(parse_expression("reduce items[] ($x; 0; .)").is_err());
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        let call = |name: &str| Expr::Call(name.to_string(), vec![]);
+        assert_eq!(
+            parse_expression("def f(g; $x): g + $x; f(a; 1), .f, g").unwrap(),
+            Expr::Def(
+                Rc::new(Definition {
+                    name: "f".to_string(),
+                    params: vec!["g".to_string(), "x".to_string()],
+                    body: Expr::Bind(
+                        Box::new(call("x")),
+                        "x".to_string(),
+                        Box::new(Expr::Binary(
+                            BinaryOp::Add,
+                            Box::new(call("g")),
+                            Box::new(Expr::Variable("x".to_string()))
+                        ))
+                    ),
+                }),
+                Box::new(Expr::Comma(
+                    Box::new(Expr::Comma(
+                        Box::new(Expr::Call(
+                            "f".to_string(),
+                            vec![path(vec![key("a")]), Expr::Literal(Value::from(1))]
+                        )),
+                        Box::new(path(vec![key("f")]))
+                    )),
+                    Box::new(path(vec![key("g")]))
+                ))
+            )
+        );
+        assert_eq!(
+            parse_expression("def f: f; f").unwrap(),
+            Expr::Def(
+                Rc::new(Definition {
+                    name: "f".to_string(),
+                    params: vec![],
+                    body: call("f"),
+                }),
+                Box::new(call("f"))
+            )
+        );
+        assert!(parse_expression("def f: 1").is_err());
+        assert!(parse_expression("def (x): 1; 2").is_err());
+        assert!(parse_expression("def f(1): 1; 2").is_err());
+        assert!(parse_expression("1 + def f: 1; f").is_err());
+    }
+
+    #[test]
+    fn test_parse_qualified_call() {
+        assert_eq!(
+            parse_expression("lib::f(1), lib::g").unwrap(),
+            Expr::Comma(
+                Box::new(Expr::Call(
+                    "lib::f".to_string(),
+                    vec![Expr::Literal(Value::from(1))]
+                )),
+                Box::new(Expr::Call("lib::g".to_string(), vec![]))
+            )
+        );
+        assert!(parse_expression("lib :: f").is_err());
+        // Without a search path, nothing can be imported.
+        let error = parse_expression(r#"import "lib" as lib; lib::f"#).unwrap_err();
+        assert_eq!(error.message, r#"Module "lib" not found in []"#);
+        assert_eq!((error.line, error.column), (1, 8));
+        assert!(parse_expression(r#"import "lib"; 1"#).is_err());
+        // `import` and `include` are only keywords before a string.
+        assert_eq!(
+            parse_expression("import").unwrap(),
+            path(vec![key("import")])
+        );
+    }
+
+    #[test]
+    fn test_parse_call() {
+        assert_eq!(
+            parse_expression("f(a; 1).b").unwrap(),
+            Expr::Path(
+                Box::new(Expr::Call(
+                    "f".to_string(),
+                    vec![path(vec![key("a")]), Expr::Literal(Value::from(1))]
+                )),
+                vec![key("b")]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_bare_builtin() {
+        assert_eq!(
+            parse_expression("people | length, .length, lengths").unwrap(),
+            Expr::Pipe(
+                Box::new(path(vec![key("people")])),
+                Box::new(Expr::Comma(
+                    Box::new(Expr::Comma(
+                        Box::new(Expr::Call("length".to_string(), vec![])),
+                        Box::new(path(vec![key("length")]))
+                    )),
+                    Box::new(path(vec![key("lengths")]))
+                ))
+            )
+        );
+        // Followed by a segment, a built-in's name is the start of a path.
+        assert_eq!(
+            parse_expression("type.x, values.0, keys[1], count..n").unwrap(),
+            Expr::Comma(
+                Box::new(Expr::Comma(
+                    Box::new(Expr::Comma(
+                        Box::new(path(vec![key("type"), key("x")])),
+                        Box::new(path(vec![key("values"), key("0")]))
+                    )),
+                    Box::new(path(vec![key("keys"), Token::Index(1)]))
+                )),
+                Box::new(path(vec![key("count"), Token::Recurse(Box::new(key("n")))]))
+            )
+        );
+        // Separated by a space, a segment is an error rather than a third
+        // reading; `keys | .x` or `.keys.x` say which is meant.
+        assert_eq!(
+            parse_expression("keys .x").unwrap_err().to_string(),
+            "Unexpected '.' after a space following keys at line 1, column 6"
+        );
+        assert_eq!(
+            parse_expression("values [0]").unwrap_err().to_string(),
+            "Unexpected '[' after a space following values at line 1, column 8"
+        );
+        assert_eq!(
+            parse_expression("a ..b").unwrap_err().to_string(),
+            "Unexpected '..' after a space following a at line 1, column 3"
+        );
+    }
+
+    #[test]
+    fn test_parse_wrong_arity() {
+        assert_eq!(
+            parse_expression("def f(x): x; f").unwrap_err().to_string(),
+            "Wrong number of arguments for f: expected 1, found 0 at line 1, column 14"
+        );
+        assert_eq!(
+            parse_expression("def f: 1; def f(a; b): 2; f(3)")
+                .unwrap_err()
+                .to_string(),
+            "Wrong number of arguments for f: expected 0 or 2, found 1 at line 1, column 27"
+        );
+        // Names that aren't defined are keys, or unknown functions when
+        // evaluated.
+        assert!(parse_expression("g, g(1)").is_ok());
+    }
+
+    #[test]
+    fn test_parse_parenthesized_path() {
+        assert_eq!(
+            parse_expression("(a).b").unwrap(),
+            Expr::Path(Box::new(path(vec![key("a")])), vec![key("b")])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_expression("a.").unwrap_err().to_string(),
+            "Expected a key or '*', found end of input at line 1, column 3"
+        );
+        assert_eq!(
+            parse_expression("a b").unwrap_err().to_string(),
+            "Unexpected identifier b at line 1, column 3"
+        );
+        assert!(parse_expression("").is_err());
+        assert!(parse_expression("(a").is_err());
+        assert!(parse_expression("f(a;)").is_err());
+    }
+}
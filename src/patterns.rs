@@ -0,0 +1,466 @@
+use crate::ast::{BinaryOp, Expr};
+use crate::builtins::text;
+use crate::filter::{combinations, eval, Options};
+use crate::scope::Scope;
+use crate::value::describe;
+use crate::{Result, YajqError};
+use regex::{Captures, Regex, RegexBuilder};
+use serde_json::{json, Map, Value};
+
+/// A regular expression compiled with the flags it was given:
+///
+/// - `g`: every match rather than just the first
+/// - `i`: case-insensitive
+/// - `x`: extended, ignoring whitespace and `#` comments in the pattern
+/// - `m`: multiline, `^` and `$` matching at the start and end of lines
+/// - `s`: single line, `.` matching newlines too
+/// - `n`: ignoring empty matches
+struct Pattern {
+    regex: Regex,
+    global: bool,
+    skip_empty: bool,
+}
+
+impl Pattern {
+    /// Compiles the regex and optional flags given to the function `name`.
+    fn new(regex: &Value, flags: Option<&Value>, name: &str) -> Result<Pattern> {
+        let source = match regex {
+            Value::String(source) => source,
+            _ => {
+                return Err(YajqError::Type(format!(
+                    "{} needs a string regex, not {}",
+                    name,
+                    describe(regex)
+                )))
+            }
+        };
+        let flags = match flags {
+            None | Some(Value::Null) => "",
+            Some(Value::String(flags)) => flags,
+            Some(flags) => {
+                return Err(YajqError::Type(format!(
+                    "{} needs a string of flags, not {}",
+                    name,
+                    describe(flags)
+                )))
+            }
+        };
+        let mut builder = RegexBuilder::new(source);
+        let (mut global, mut skip_empty) = (false, false);
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'n' => skip_empty = true,
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                'x' => {
+                    builder.ignore_whitespace(true);
+                }
+                'm' => {
+                    builder.multi_line(true);
+                }
+                's' => {
+                    builder.dot_matches_new_line(true);
+                }
+                _ => {
+                    return Err(YajqError::Regex(format!(
+                        "{} is not a valid flag (in {:?})",
+                        flag, flags
+                    )))
+                }
+            }
+        }
+        let regex = builder
+            .build()
+            .map_err(|e| YajqError::Regex(e.to_string()))?;
+        Ok(Pattern {
+            regex,
+            global,
+            skip_empty,
+        })
+    }
+
+    /// The first match in `text`, or every match if the `g` flag was given.
+    fn captures<'t>(&self, text: &'t str) -> Vec<Captures<'t>> {
+        let matches = self
+            .regex
+            .captures_iter(text)
+            .filter(|captures| !(self.skip_empty && captures[0].is_empty()));
+        if self.global {
+            matches.collect()
+        } else {
+            matches.take(1).collect()
+        }
+    }
+
+    /// `{offset, length, string, captures}` for a match, with offsets and
+    /// lengths in characters. Groups that didn't take part in the match have
+    /// an offset of -1 and a `null` string.
+    fn describe(&self, text: &str, captures: &Captures) -> Value {
+        let whole = &captures[0];
+        let groups: Vec<Value> = self
+            .regex
+            .capture_names()
+            .enumerate()
+            .skip(1)
+            .map(|(group, name)| match captures.get(group) {
+                Some(found) => json!({
+                    "offset": text[..found.start()].chars().count(),
+                    "length": found.as_str().chars().count(),
+                    "string": found.as_str(),
+                    "name": name,
+                }),
+                None => json!({"offset": -1, "length": 0, "string": null, "name": name}),
+            })
+            .collect();
+        json!({
+            "offset": text[..captures.get(0).unwrap().start()].chars().count(),
+            "length": whole.chars().count(),
+            "string": whole,
+            "captures": groups,
+        })
+    }
+
+    /// An object of the named groups of a match.
+    fn named(&self, captures: &Captures) -> Value {
+        self.regex
+            .capture_names()
+            .flatten()
+            .map(|name| {
+                let value = captures
+                    .name(name)
+                    .map_or(Value::Null, |found| Value::from(found.as_str()));
+                (name.to_string(), value)
+            })
+            .collect::<Map<String, Value>>()
+            .into()
+    }
+}
+
+/// `test(regex)` or `test(regex; flags)`: whether the regex matches.
+pub fn test(input: &Value, arguments: &[Value]) -> Result<Value> {
+    let pattern = Pattern::new(&arguments[0], arguments.get(1), "test")?;
+    Ok(Value::Bool(
+        !pattern.captures(text(input, "test")?).is_empty(),
+    ))
+}
+
+/// Calls `f` with the pattern and the input text for every combination of
+/// the outputs of the regex and flags arguments.
+fn each_pattern(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    name: &str,
+    f: &mut dyn FnMut(&Pattern, &str) -> Result<()>,
+) -> Result<()> {
+    let text = text(input, name)?;
+    combinations(
+        arguments,
+        input,
+        options,
+        scope,
+        &mut Vec::new(),
+        &mut |values| f(&Pattern::new(&values[0], values.get(1), name)?, text),
+    )
+}
+
+/// `match(regex; flags)`: a description of each match.
+pub fn matches(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    each_pattern(
+        input,
+        arguments,
+        options,
+        scope,
+        "match",
+        &mut |pattern, text| {
+            for captures in pattern.captures(text) {
+                out(pattern.describe(text, &captures))?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// `capture(regex; flags)`: an object of the named groups of each match.
+pub fn capture(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    each_pattern(
+        input,
+        arguments,
+        options,
+        scope,
+        "capture",
+        &mut |pattern, text| {
+            for captures in pattern.captures(text) {
+                out(pattern.named(&captures))?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// `scan(regex; flags)`: every match, as a string, or as an array of the
+/// strings of its groups if the regex has any.
+pub fn scan(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    each_pattern(
+        input,
+        arguments,
+        options,
+        scope,
+        "scan",
+        &mut |pattern, text| {
+            for captures in pattern.regex.captures_iter(text) {
+                if pattern.skip_empty && captures[0].is_empty() {
+                    continue;
+                }
+                out(if captures.len() == 1 {
+                    Value::from(&captures[0])
+                } else {
+                    captures
+                        .iter()
+                        .skip(1)
+                        .map(|group| group.map_or(Value::Null, |group| Value::from(group.as_str())))
+                        .collect()
+                })?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// `sub(regex; replacement; flags)`: the input with the first match, or
+/// every match with the `g` flag, replaced. The replacement is evaluated
+/// against the object of the match's named groups, and must output a single
+/// string.
+pub fn sub(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    substitute(input, arguments, options, scope, "sub", "", out)
+}
+
+/// `gsub(regex; replacement; flags)`: `sub` with the `g` flag.
+pub fn gsub(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    substitute(input, arguments, options, scope, "gsub", "g", out)
+}
+
+/// Replaces matches for `sub` and `gsub`, which adds `extra_flags` to those
+/// given.
+fn substitute(
+    input: &Value,
+    arguments: &[Expr],
+    options: &Options,
+    scope: &Scope,
+    name: &str,
+    extra_flags: &str,
+    out: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let replacement = &arguments[1];
+    let flags = match arguments.get(2) {
+        Some(flags) if !extra_flags.is_empty() => Expr::Binary(
+            BinaryOp::Add,
+            Box::new(flags.to_owned()),
+            Box::new(Expr::Literal(Value::from(extra_flags))),
+        ),
+        Some(flags) => flags.to_owned(),
+        None => Expr::Literal(Value::from(extra_flags)),
+    };
+    let pattern_arguments = [arguments[0].to_owned(), flags];
+    each_pattern(
+        input,
+        &pattern_arguments,
+        options,
+        scope,
+        name,
+        &mut |pattern, text| {
+            let mut result = String::new();
+            let mut last = 0;
+            for captures in pattern.captures(text) {
+                let whole = captures.get(0).unwrap();
+                result.push_str(&text[last..whole.start()]);
+                let mut replaced = Vec::new();
+                eval(
+                    replacement,
+                    &pattern.named(&captures),
+                    options,
+                    scope,
+                    &mut |value| {
+                        replaced.push(value);
+                        Ok(())
+                    },
+                )?;
+                match replaced.as_slice() {
+                    [Value::String(replaced)] => result.push_str(replaced),
+                    _ => {
+                        return Err(YajqError::Type(format!(
+                            "{} needs a replacement that outputs one string, not {}",
+                            name,
+                            describe(&Value::Array(replaced))
+                        )))
+                    }
+                }
+                last = whole.end();
+            }
+            result.push_str(&text[last..]);
+            out(Value::from(result))
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::call_;
+    use serde_json::json;
+
+    #[test]
+    fn test_test() {
+        assert_eq!(
+            call_(
+                json!("Order ID-42 shipped"),
+                r#"test("id-\\d+"), test("id-\\d+"; "i"), test("^shipped"), test("^shipped$"; "m")"#
+            )
+            .unwrap(),
+            vec![json!(false), json!(true), json!(false), json!(false)]
+        );
+        assert_eq!(
+            call_(
+                json!("a\nb"),
+                r#"test("^b$"; "m"), test("a.b"), test("a.b"; "s")"#
+            )
+            .unwrap(),
+            vec![json!(true), json!(false), json!(true)]
+        );
+        assert_eq!(
+            call_(json!("abc"), r#"test("a b  c # letters"; "x")"#).unwrap(),
+            vec![json!(true)]
+        );
+        assert_eq!(
+            call_(json!("a"), r#"test("a"; "q")"#)
+                .unwrap_err()
+                .to_string(),
+            r#"Regex Error: q is not a valid flag (in "q")"#
+        );
+        assert!(call_(json!("a"), r#"test("(")"#)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Regex Error: "));
+        assert_eq!(
+            call_(json!(1), r#"test("1")"#).unwrap_err().to_string(),
+            "Type Error: test needs a string, not number (1)"
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(
+            call_(json!("zoë-7 x"), r#"match("(?<letter>[a-zë]+)-(\\d)(y)?")"#).unwrap(),
+            vec![json!({
+                "offset": 0,
+                "length": 5,
+                "string": "zoë-7",
+                "captures": [
+                    {"offset": 0, "length": 3, "string": "zoë", "name": "letter"},
+                    {"offset": 4, "length": 1, "string": "7", "name": null},
+                    {"offset": -1, "length": 0, "string": null, "name": null}
+                ]
+            })]
+        );
+        assert_eq!(
+            call_(json!("ëa ëb"), r#"[match("ë(.)"; "g") | .offset]"#).unwrap(),
+            vec![json!([0, 3])]
+        );
+        assert_eq!(
+            call_(json!("abc"), r#"[match("x")]"#).unwrap(),
+            vec![json!([])]
+        );
+    }
+
+    #[test]
+    fn test_capture_and_scan() {
+        let log = json!("user=alice id=7 user=bob id=");
+        assert_eq!(
+            call_(
+                log.clone(),
+                r#"capture("user=(?<user>\\w+) id=(?<id>\\d+)?"; "g")"#
+            )
+            .unwrap(),
+            vec![
+                json!({"user": "alice", "id": "7"}),
+                json!({"user": "bob", "id": null})
+            ]
+        );
+        assert_eq!(
+            call_(log.clone(), r#"[scan("\\d+")], [scan("(\\w+)=(\\d)")]"#).unwrap(),
+            vec![json!(["7"]), json!([["id", "7"]])]
+        );
+        assert_eq!(
+            call_(json!("aB"), r#"[scan("b"; "i")], [scan("x*"; "n")]"#).unwrap(),
+            vec![json!(["B"]), json!([])]
+        );
+    }
+
+    #[test]
+    fn test_sub_and_gsub() {
+        let data = json!("2021-03-04 and 2022-05-06");
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"sub("(?<y>\\d+)-(?<m>\\d+)-(?<d>\\d+)"; .d + "/" + .m + "/" + .y)"#
+            )
+            .unwrap(),
+            vec![json!("04/03/2021 and 2022-05-06")]
+        );
+        assert_eq!(
+            call_(data.clone(), r##"gsub("\\d"; "#")"##).unwrap(),
+            vec![json!("####-##-## and ####-##-##")]
+        );
+        assert_eq!(
+            call_(
+                data.clone(),
+                r#"sub("AND"; "or"; "i"), gsub("AND"; "or"; "i")"#
+            )
+            .unwrap(),
+            vec![
+                json!("2021-03-04 or 2022-05-06"),
+                json!("2021-03-04 or 2022-05-06")
+            ]
+        );
+        assert_eq!(
+            call_(json!("ab"), r#"gsub(""; "-")"#).unwrap(),
+            vec![json!("-a-b-")]
+        );
+        assert_eq!(
+            call_(data, r#"sub("\\d"; empty)"#).unwrap_err().to_string(),
+            "Type Error: sub needs a replacement that outputs one string, not array ([])"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::ast::{Definition, Expr};
+use serde_json::Value;
+use std::rc::Rc;
+
+/// The variables and functions visible to an expression. Scopes are
+/// persistent linked lists, so binding a name shares the enclosing scope
+/// instead of copying it.
+#[derive(Clone, Debug, Default)]
+pub struct Scope(Option<Rc<Frame>>);
+
+#[derive(Debug)]
+struct Frame {
+    name: String,
+    binding: Binding,
+    parent: Scope,
+}
+
+#[derive(Debug)]
+enum Binding {
+    Variable(Value),
+    Function(Rc<Definition>),
+    Closure(Expr, Scope),
+    /// A function imported from a module, with the module's scope at its
+    /// definition
+    Imported(Rc<Definition>, Scope),
+}
+
+/// A function found in a scope.
+pub enum Function<'a> {
+    /// A `def`, with the scope it was defined in, which includes the function
+    /// itself so that it can recurse
+    Defined(&'a Definition, Scope),
+    /// A filter passed as an argument, with the scope of the caller that
+    /// passed it
+    Closure(&'a Expr, &'a Scope),
+}
+
+impl Scope {
+    /// A scope in which `$name` is `value`, shadowing any outer `$name`.
+    pub fn bind(&self, name: &str, value: Value) -> Scope {
+        self.push(name, Binding::Variable(value))
+    }
+
+    /// A scope in which `definition` can be called.
+    pub fn define(&self, definition: Rc<Definition>) -> Scope {
+        let name = definition.name.clone();
+        self.push(&name, Binding::Function(definition))
+    }
+
+    /// A scope in which the parameter `name` evaluates `expr` in `scope`.
+    pub fn bind_closure(&self, name: &str, expr: Expr, scope: Scope) -> Scope {
+        self.push(name, Binding::Closure(expr, scope))
+    }
+
+    /// A scope in which each function defined in `module` can be called as
+    /// `alias::name`.
+    pub fn import(&self, alias: &str, module: &Scope) -> Scope {
+        let definitions: Vec<_> = module
+            .frames()
+            .filter_map(|(scope, frame)| match &frame.binding {
+                Binding::Function(definition) => Some((definition, scope)),
+                _ => None,
+            })
+            .collect();
+        // Outermost first, so that later definitions shadow earlier ones.
+        definitions
+            .into_iter()
+            .rev()
+            .fold(self.clone(), |scope, (definition, defined)| {
+                scope.push(
+                    &format!("{}::{}", alias, definition.name),
+                    Binding::Imported(definition.clone(), defined.clone()),
+                )
+            })
+    }
+
+    fn push(&self, name: &str, binding: Binding) -> Scope {
+        Scope(Some(Rc::new(Frame {
+            name: name.to_string(),
+            binding,
+            parent: self.clone(),
+        })))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.frames().find_map(|(_, frame)| match &frame.binding {
+            Binding::Variable(value) if frame.name == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// The innermost function or parameter called `name` taking `arity`
+    /// arguments; parameters never take any.
+    pub fn function(&self, name: &str, arity: usize) -> Option<Function<'_>> {
+        self.frames()
+            .find_map(|(scope, frame)| match &frame.binding {
+                Binding::Function(definition)
+                    if frame.name == name && definition.params.len() == arity =>
+                {
+                    Some(Function::Defined(definition, scope.clone()))
+                }
+                Binding::Imported(definition, defined)
+                    if frame.name == name && definition.params.len() == arity =>
+                {
+                    Some(Function::Defined(definition, defined.clone()))
+                }
+                Binding::Closure(expr, scope) if frame.name == name && arity == 0 => {
+                    Some(Function::Closure(expr, scope))
+                }
+                _ => None,
+            })
+    }
+
+    /// Each frame from the innermost out, with the scope that starts at it.
+    fn frames(&self) -> impl Iterator<Item = (&Scope, &Frame)> {
+        let mut scope = self;
+        std::iter::from_fn(move || {
+            let frame = scope.0.as_deref()?;
+            let current = scope;
+            scope = &frame.parent;
+            Some((current, frame))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_scope_shadowing() {
+        let outer = Scope::default().bind("a", json!(1)).bind("b", json!(2));
+        let inner = outer.bind("a", json!(3));
+        assert_eq!(inner.get("a"), Some(&json!(3)));
+        assert_eq!(inner.get("b"), Some(&json!(2)));
+        assert_eq!(outer.get("a"), Some(&json!(1)));
+        assert_eq!(outer.get("c"), None);
+    }
+
+    #[test]
+    fn test_scope_functions() {
+        let definition = |params: &[&str]| {
+            Rc::new(Definition {
+                name: "f".to_string(),
+                params: params.iter().map(|param| param.to_string()).collect(),
+                body: Expr::Identity,
+            })
+        };
+        let scope = Scope::default()
+            .define(definition(&[]))
+            .define(definition(&["x"]))
+            .bind("f", json!(1));
+        assert!(matches!(
+            scope.function("f", 0),
+            Some(Function::Defined(definition, _)) if definition.params.is_empty()
+        ));
+        assert!(matches!(
+            scope.function("f", 1),
+            Some(Function::Defined(..))
+        ));
+        assert!(scope.function("f", 2).is_none());
+        let scope = scope.bind_closure("f", Expr::Identity, Scope::default());
+        assert!(matches!(
+            scope.function("f", 0),
+            Some(Function::Closure(..))
+        ));
+        assert_eq!(scope.get("f"), Some(&json!(1)));
+    }
+
+    #[test]
+    fn test_scope_import() {
+        let definition = |name: &str| {
+            Rc::new(Definition {
+                name: name.to_string(),
+                params: vec![],
+                body: Expr::Identity,
+            })
+        };
+        let module = Scope::default()
+            .define(definition("f"))
+            .define(definition("g"));
+        let scope = Scope::default().bind("a", json!(1)).import("lib", &module);
+        assert!(scope.function("lib::f", 0).is_some());
+        assert!(scope.function("lib::g", 0).is_some());
+        assert!(scope.function("f", 0).is_none());
+        // `g` is called in the module's scope, where `f` is defined.
+        match scope.function("lib::g", 0) {
+            Some(Function::Defined(_, defined)) => assert!(defined.function("f", 0).is_some()),
+            _ => panic!("lib::g is not defined"),
+        }
+        assert_eq!(scope.get("a"), Some(&json!(1)));
+    }
+}
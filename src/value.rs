@@ -0,0 +1,397 @@
+use crate::ast::BinaryOp;
+use crate::{Result, YajqError};
+use serde_json::{Number, Value};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+/// Totally orders JSON values: `null < false < true < numbers < strings <
+/// arrays < objects`. Numbers compare numerically (`1 == 1.0`), strings by
+/// code point, arrays element-wise, and objects first by their sorted key sets
+/// and then by the values under those keys.
+pub fn compare(left: &Value, right: &Value) -> Ordering {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => compare_numbers(left, right),
+        (Value::String(left), Value::String(right)) => left.cmp(right),
+        (Value::Bool(left), Value::Bool(right)) => left.cmp(right),
+        (Value::Array(left), Value::Array(right)) => left
+            .iter()
+            .zip(right)
+            .map(|(left, right)| compare(left, right))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| left.len().cmp(&right.len())),
+        (Value::Object(left), Value::Object(right)) => {
+            left.keys().cmp(right.keys()).then_with(|| {
+                left.values()
+                    .zip(right.values())
+                    .map(|(left, right)| compare(left, right))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            })
+        }
+        _ => rank(left).cmp(&rank(right)),
+    }
+}
+
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+/// Compares integers exactly and falls back to floating point otherwise.
+pub fn compare_numbers(left: &Number, right: &Number) -> Ordering {
+    if let (Some(left), Some(right)) = (left.as_i64(), right.as_i64()) {
+        return left.cmp(&right);
+    }
+    if let (Some(left), Some(right)) = (left.as_u64(), right.as_u64()) {
+        return left.cmp(&right);
+    }
+    let (left, right) = (left.as_f64().unwrap(), right.as_f64().unwrap());
+    left.partial_cmp(&right).unwrap_or(Ordering::Equal)
+}
+
+/// Applies an arithmetic operator. Besides numbers, `+` concatenates strings
+/// and arrays and merges objects (the right side winning), `-` removes the
+/// elements of the right array from the left one, `*` merges objects
+/// recursively, and `null` is the identity of `+`.
+pub fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Result<Value> {
+    match (op, left, right) {
+        (_, Value::Number(l), Value::Number(r)) => arithmetic_numbers(op, l, r),
+        (BinaryOp::Add, Value::Null, value) | (BinaryOp::Add, value, Value::Null) => {
+            Ok(value.to_owned())
+        }
+        (BinaryOp::Add, Value::String(l), Value::String(r)) => Ok(Value::from(l.to_owned() + r)),
+        (BinaryOp::Add, Value::Array(l), Value::Array(r)) => {
+            Ok(Value::Array(l.iter().chain(r).cloned().collect()))
+        }
+        (BinaryOp::Add, Value::Object(l), Value::Object(r)) => {
+            let mut merged = l.to_owned();
+            merged.extend(r.to_owned());
+            Ok(Value::Object(merged))
+        }
+        (BinaryOp::Sub, Value::Array(l), Value::Array(r)) => Ok(Value::Array(
+            l.iter()
+                .filter(|l| !r.iter().any(|r| compare(l, r) == Ordering::Equal))
+                .cloned()
+                .collect(),
+        )),
+        (BinaryOp::Mul, Value::Object(_), Value::Object(_)) => Ok(merge(left, right)),
+        _ => Err(YajqError::Type(format!(
+            "Cannot apply {} to {} and {}",
+            symbol(op),
+            describe(left),
+            describe(right)
+        ))),
+    }
+}
+
+/// `-value`
+pub fn negate(value: &Value) -> Result<Value> {
+    match value {
+        Value::Number(number) => arithmetic_numbers(BinaryOp::Sub, &Number::from(0), number),
+        _ => Err(YajqError::Type(format!(
+            "Cannot negate {}",
+            describe(value)
+        ))),
+    }
+}
+
+/// Recursively merges two objects; anything else is replaced by `right`.
+fn merge(left: &Value, right: &Value) -> Value {
+    match (left, right) {
+        (Value::Object(l), Value::Object(r)) => {
+            let mut merged = l.to_owned();
+            for (key, value) in r {
+                let value = match merged.get(key) {
+                    Some(existing) => merge(existing, value),
+                    None => value.to_owned(),
+                };
+                merged.insert(key.to_owned(), value);
+            }
+            Value::Object(merged)
+        }
+        _ => right.to_owned(),
+    }
+}
+
+fn symbol(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        _ => unreachable!("not an arithmetic operator"),
+    }
+}
+
+/// Computes integer results exactly, failing rather than rounding when they
+/// don't fit in an `i64` or `u64`, and falls back to floating point when
+/// either side isn't an integer or a division has a remainder.
+fn arithmetic_numbers(op: BinaryOp, left: &Number, right: &Number) -> Result<Value> {
+    if let (Some(l), Some(r)) = (integer(left), integer(right)) {
+        let result = match op {
+            BinaryOp::Add => Some(l + r),
+            BinaryOp::Sub => Some(l - r),
+            BinaryOp::Mul => l.checked_mul(r),
+            BinaryOp::Div | BinaryOp::Rem if r == 0 => return Err(division_by_zero(left, op)),
+            BinaryOp::Div if l % r != 0 => return float(l as f64 / r as f64, left, op, right),
+            BinaryOp::Div => Some(l / r),
+            BinaryOp::Rem => Some(l % r),
+            _ => unreachable!("not an arithmetic operator"),
+        };
+        return result
+            .and_then(|result| {
+                i64::try_from(result)
+                    .map(Value::from)
+                    .or_else(|_| u64::try_from(result).map(Value::from))
+                    .ok()
+            })
+            .ok_or_else(|| {
+                YajqError::Arithmetic(format!("{} {} {} overflows", left, symbol(op), right))
+            });
+    }
+    let (l, r) = (left.as_f64().unwrap(), right.as_f64().unwrap());
+    let result = match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Sub => l - r,
+        BinaryOp::Mul => l * r,
+        BinaryOp::Div | BinaryOp::Rem if r == 0.0 => return Err(division_by_zero(left, op)),
+        BinaryOp::Div => l / r,
+        BinaryOp::Rem => l % r,
+        _ => unreachable!("not an arithmetic operator"),
+    };
+    float(result, left, op, right)
+}
+
+/// Adds up numbers, exactly if they are all integers and the total fits in
+/// an `i64` or `u64`, and in floating point otherwise.
+pub fn sum(numbers: &[&Number]) -> Result<Value> {
+    let exact = numbers
+        .iter()
+        .try_fold(0i128, |total, number| total.checked_add(integer(number)?))
+        .and_then(|total| {
+            i64::try_from(total)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(total).map(Value::from))
+                .ok()
+        });
+    if let Some(total) = exact {
+        return Ok(total);
+    }
+    let total: f64 = numbers.iter().map(|number| number.as_f64().unwrap()).sum();
+    Number::from_f64(total)
+        .map(Value::Number)
+        .ok_or_else(|| YajqError::Arithmetic("The sum is not a finite number".to_string()))
+}
+
+fn integer(number: &Number) -> Option<i128> {
+    number
+        .as_i64()
+        .map(i128::from)
+        .or_else(|| number.as_u64().map(i128::from))
+}
+
+fn float(result: f64, left: &Number, op: BinaryOp, right: &Number) -> Result<Value> {
+    Number::from_f64(result).map(Value::Number).ok_or_else(|| {
+        YajqError::Arithmetic(format!(
+            "{} {} {} is not a finite number",
+            left,
+            symbol(op),
+            right
+        ))
+    })
+}
+
+fn division_by_zero(left: &Number, op: BinaryOp) -> YajqError {
+    YajqError::Arithmetic(format!("{} {} 0 divides by zero", left, symbol(op)))
+}
+
+/// The name of the value's type, as output by the `type` built-in.
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Describes a value for error messages, e.g. `string ("abc")`, shortening
+/// long values.
+pub fn describe(value: &Value) -> String {
+    let json = value.to_string();
+    let json = match json.char_indices().nth(DESCRIBED_LENGTH) {
+        Some((end, _)) => format!("{}...", &json[..end]),
+        None => json,
+    };
+    format!("{} ({})", type_name(value), json)
+}
+
+const DESCRIBED_LENGTH: usize = 30;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_compare_across_types() {
+        let ordered = vec![
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1),
+            json!(0.5),
+            json!(u64::MAX),
+            json!(""),
+            json!("a"),
+            json!([]),
+            json!([1]),
+            json!([1, 2]),
+            json!({}),
+            json!({"a": 2}),
+            json!({"a": 1, "b": 0}),
+            json!({"b": 0}),
+        ];
+        for (i, left) in ordered.iter().enumerate() {
+            for (j, right) in ordered.iter().enumerate() {
+                assert_eq!(compare(left, right), i.cmp(&j), "{} vs {}", left, right);
+            }
+        }
+    }
+
+    fn arithmetic_(op: BinaryOp, left: Value, right: Value) -> Value {
+        arithmetic(op, &left, &right).unwrap()
+    }
+
+    #[test]
+    fn test_integer_arithmetic() {
+        assert_eq!(arithmetic_(BinaryOp::Add, json!(1), json!(2)), json!(3));
+        assert_eq!(
+            arithmetic_(BinaryOp::Add, json!(i64::MAX), json!(1)),
+            json!(i64::MAX as u64 + 1)
+        );
+        assert_eq!(
+            arithmetic_(BinaryOp::Sub, json!(u64::MAX), json!(u64::MAX - 1)),
+            json!(1)
+        );
+        assert_eq!(arithmetic_(BinaryOp::Sub, json!(0), json!(5)), json!(-5));
+        assert_eq!(arithmetic_(BinaryOp::Mul, json!(-4), json!(3)), json!(-12));
+        assert_eq!(arithmetic_(BinaryOp::Div, json!(12), json!(4)), json!(3));
+        assert_eq!(arithmetic_(BinaryOp::Div, json!(7), json!(2)), json!(3.5));
+        assert_eq!(arithmetic_(BinaryOp::Rem, json!(-7), json!(3)), json!(-1));
+        assert_eq!(
+            arithmetic(BinaryOp::Mul, &json!(u64::MAX), &json!(2))
+                .unwrap_err()
+                .to_string(),
+            "Arithmetic Error: 18446744073709551615 * 2 overflows"
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Rem, &json!(1), &json!(0))
+                .unwrap_err()
+                .to_string(),
+            "Arithmetic Error: 1 % 0 divides by zero"
+        );
+        assert_eq!(negate(&json!(i64::MIN)).unwrap(), json!(1u64 << 63));
+        assert!(negate(&json!(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        assert_eq!(arithmetic_(BinaryOp::Add, json!(0.5), json!(1)), json!(1.5));
+        assert_eq!(arithmetic_(BinaryOp::Mul, json!(1.5), json!(2)), json!(3.0));
+        assert_eq!(arithmetic_(BinaryOp::Rem, json!(5.5), json!(2)), json!(1.5));
+        assert!(arithmetic(BinaryOp::Div, &json!(1.5), &json!(0.0)).is_err());
+        assert!(arithmetic(BinaryOp::Mul, &json!(1e300), &json!(1e300)).is_err());
+    }
+
+    #[test]
+    fn test_non_numeric_arithmetic() {
+        assert_eq!(
+            arithmetic_(BinaryOp::Add, json!("ab"), json!("c")),
+            json!("abc")
+        );
+        assert_eq!(
+            arithmetic_(BinaryOp::Add, json!([1]), json!([1, 2])),
+            json!([1, 1, 2])
+        );
+        assert_eq!(arithmetic_(BinaryOp::Add, json!(null), json!(2)), json!(2));
+        assert_eq!(
+            arithmetic_(
+                BinaryOp::Add,
+                json!({"a": {"b": 1}, "c": 2}),
+                json!({"a": {"d": 3}})
+            ),
+            json!({"a": {"d": 3}, "c": 2})
+        );
+        assert_eq!(
+            arithmetic_(
+                BinaryOp::Mul,
+                json!({"a": {"b": 1}, "c": 2}),
+                json!({"a": {"d": 3}})
+            ),
+            json!({"a": {"b": 1, "d": 3}, "c": 2})
+        );
+        assert_eq!(
+            arithmetic_(BinaryOp::Sub, json!([1, 2, 1, 3]), json!([1.0, 3])),
+            json!([2])
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Sub, &json!("a"), &json!(1))
+                .unwrap_err()
+                .to_string(),
+            r#"Type Error: Cannot apply - to string ("a") and number (1)"#
+        );
+    }
+
+    #[test]
+    fn test_sum() {
+        let sum_of = |values: Value| {
+            let values = values.as_array().unwrap().to_owned();
+            let numbers: Vec<&Number> = values
+                .iter()
+                .map(|value| match value {
+                    Value::Number(number) => number,
+                    _ => unreachable!(),
+                })
+                .collect();
+            sum(&numbers).unwrap()
+        };
+        assert_eq!(sum_of(json!([])), json!(0));
+        assert_eq!(
+            sum_of(json!([9007199254740993i64, 1, -2])),
+            json!(9007199254740992i64)
+        );
+        assert_eq!(sum_of(json!([i64::MAX, i64::MAX])), json!(u64::MAX - 1));
+        assert_eq!(sum_of(json!([u64::MAX, 1])), json!(1.8446744073709552e19));
+        assert_eq!(sum_of(json!([1, 0.5])), json!(1.5));
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(&json!("abc")), r#"string ("abc")"#);
+        assert_eq!(
+            describe(&json!({"a": "a very long string that goes on"})),
+            r#"object ({"a":"a very long string that ...)"#
+        );
+    }
+
+    #[test]
+    fn test_compare_numbers() {
+        assert_eq!(compare(&json!(1), &json!(1.0)), Ordering::Equal);
+        assert_eq!(
+            compare(&json!(i64::MAX), &json!(i64::MAX - 1)),
+            Ordering::Greater
+        );
+        assert_eq!(compare(&json!(-1), &json!(u64::MAX)), Ordering::Less);
+    }
+}